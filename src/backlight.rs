@@ -1,18 +1,16 @@
 use std::{
     fs::{File, OpenOptions, self},
     path::{PathBuf, Path},
-    time::Instant,
+    time::{Duration, Instant},
     io::Write,
     cmp::min,
 };
 use anyhow::{Result, anyhow};
 use input::event::{
-    Event, switch::{Switch, SwitchEvent, SwitchState},
+    Event, switch::{Switch, SwitchEvent, SwitchState}, touch::TouchEvent,
 };
-use crate::TIMEOUT_MS;
+use crate::{Config, config::{IdleMode, WakeBrightness}};
 
-const BRIGHTNESS_DIM_TIMEOUT: i32 = TIMEOUT_MS * 3; // should be a multiple of TIMEOUT_MS
-const BRIGHTNESS_OFF_TIMEOUT: i32 = TIMEOUT_MS * 6; // should be a multiple of TIMEOUT_MS
 const DEFAULT_BRIGHTNESS: u32 = 128;
 const DIMMED_BRIGHTNESS: u32 = 1;
 
@@ -43,26 +41,54 @@ fn set_backlight(mut file: &File, value: u32) {
 
 pub struct BacklightManager {
     last_active: Instant,
+    // when the backlight last went back to full brightness
+    woke_at: Instant,
     max_bl: u32,
     current_bl: u32,
+    // the brightness read back just before the bar last dimmed or turned off
+    last_bl: Option<u32>,
+    // last_bl restored by WakeBrightness = "Last" when the bar woke, until the layer changes
+    restored_bl: Option<u32>,
+    layer_bl: Option<u32>,
+    // whether the bar is at full brightness rather than dimmed or off
+    awake: bool,
     lid_state: SwitchState,
+    // set by the Blank action, keeps the backlight off until the next touch
+    blanked: bool,
+    // keeps the backlight off while the bar is disabled with SIGUSR1
+    disabled: bool,
+    bl_path: PathBuf,
     bl_file: File
 }
 
 impl BacklightManager {
     pub fn new() -> BacklightManager {
-        let bl_path = find_backlight().unwrap();
+        BacklightManager::open(find_backlight().unwrap())
+    }
+    fn open(bl_path: PathBuf) -> BacklightManager {
         let bl_file = OpenOptions::new().write(true).open(bl_path.join("brightness")).unwrap();
         BacklightManager {
             bl_file,
             lid_state: SwitchState::Off,
+            blanked: false,
+            disabled: false,
             max_bl: read_attr(&bl_path, "max_brightness"),
             current_bl: read_attr(&bl_path, "brightness"),
-            last_active: Instant::now()
+            last_bl: None,
+            restored_bl: None,
+            layer_bl: None,
+            awake: false,
+            bl_path,
+            last_active: Instant::now(),
+            woke_at: Instant::now()
         }
     }
     pub fn process_event(&mut self, event: &Event) {
         match event {
+            Event::Touch(TouchEvent::Down(_)) => {
+                self.last_active = Instant::now();
+                self.blanked = false;
+            },
             Event::Keyboard(_) | Event::Pointer(_) | Event::Gesture(_) | Event::Touch(_) => {
                 self.last_active = Instant::now();
             },
@@ -81,14 +107,42 @@ impl BacklightManager {
             _ => {}
         }
     }
-    pub fn update_backlight(&mut self) {
-        let since_last_active = (Instant::now() - self.last_active).as_millis() as u64;
-        let new_bl = min(self.max_bl, if self.lid_state == SwitchState::On {
+    // layer_bl overrides the default full brightness, idle dimming still applies
+    pub fn update_backlight(&mut self, cfg: &Config, layer_bl: Option<u32>) {
+        let since_last_active = self.last_active.elapsed();
+        let max_bl = cfg.power.max_brightness.unwrap_or(u32::MAX);
+        if layer_bl != self.layer_bl {
+            // switching layers brings the brightness of the new layer
+            self.layer_bl = layer_bl;
+            self.restored_bl = None;
+        }
+        // stay on for a while after waking up even if the idle timeout has elapsed
+        let recently_woke = self.woke_at.elapsed() < cfg.backlight_min_on;
+        let off = self.lid_state == SwitchState::On || self.blanked || self.disabled;
+        let awake = !off && (cfg.power.always_on || since_last_active < cfg.power.dim_timeout || recently_woke);
+        if awake != self.awake {
+            if awake {
+                self.woke_at = Instant::now();
+                self.restored_bl = match cfg.wake_brightness {
+                    WakeBrightness::Last => self.last_bl,
+                    WakeBrightness::Max => None
+                };
+            } else {
+                // read back rather than taken from current_bl, the brightness may have been
+                // changed outside of tiny-dfr since it was last set here
+                self.last_bl = fs::read_to_string(self.bl_path.join("brightness")).ok()
+                    .and_then(|bl| bl.trim().parse().ok())
+                    .filter(|bl| *bl > 0);
+            }
+            self.awake = awake;
+        }
+        let bright_bl = min(self.restored_bl.unwrap_or(layer_bl.unwrap_or(DEFAULT_BRIGHTNESS)), max_bl);
+        let new_bl = min(self.max_bl, if off {
             0
-        } else if since_last_active < BRIGHTNESS_DIM_TIMEOUT as u64 {
-            DEFAULT_BRIGHTNESS
-        } else if since_last_active < BRIGHTNESS_OFF_TIMEOUT as u64 {
-            DIMMED_BRIGHTNESS
+        } else if awake {
+            bright_bl
+        } else if since_last_active < cfg.power.off_timeout || cfg.idle_mode != IdleMode::Off {
+            min(bright_bl, DIMMED_BRIGHTNESS)
         } else {
             0
         });
@@ -97,7 +151,136 @@ impl BacklightManager {
             set_backlight(&self.bl_file, self.current_bl);
         }
     }
+    // Time until idle dimming changes the brightness next
+    pub fn next_timeout_ms(&self, cfg: &Config) -> i32 {
+        let since_last_active = self.last_active.elapsed();
+        let idle_timeouts = [cfg.power.dim_timeout, cfg.power.off_timeout].into_iter()
+            .filter(|timeout| *timeout > since_last_active)
+            .map(|timeout| timeout - since_last_active);
+        // the bar may only dim once BacklightMinOnMs have passed since it woke
+        let min_on = cfg.backlight_min_on.checked_sub(self.woke_at.elapsed()).filter(|left| !left.is_zero());
+        idle_timeouts.chain(min_on)
+            .map(|left| (left + Duration::from_millis(1)).as_millis().min(i32::MAX as u128) as i32)
+            .min()
+            .unwrap_or(i32::MAX)
+    }
+    // Whether the bar has been idle long enough to be dimmed
+    pub fn is_idle(&self, cfg: &Config) -> bool {
+        !cfg.power.always_on
+            && self.last_active.elapsed() >= cfg.power.dim_timeout
+            && self.woke_at.elapsed() >= cfg.backlight_min_on
+    }
+    pub fn blank(&mut self) {
+        self.blanked = true;
+    }
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.disabled = !enabled;
+    }
     pub fn current_bl(&self) -> u32 {
         self.current_bl
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_bar;
+
+    // A backlight device in a temporary directory
+    struct FakeBacklight {
+        path: PathBuf
+    }
+
+    impl FakeBacklight {
+        fn new(name: &str) -> FakeBacklight {
+            let path = std::env::temp_dir().join(format!("tiny-dfr-test-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("max_brightness"), "255\n").unwrap();
+            fs::write(path.join("brightness"), "0\n").unwrap();
+            FakeBacklight { path }
+        }
+        // Changes the brightness like brightnessctl would
+        fn set(&self, value: u32) {
+            fs::write(self.path.join("brightness"), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    impl Drop for FakeBacklight {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn idle(backlight: &mut BacklightManager, cfg: &Config) {
+        backlight.last_active = Instant::now() - cfg.power.dim_timeout;
+    }
+
+    #[test]
+    fn last_brightness_is_restored_on_wake_only() {
+        let (cfg, _) = test_bar("WakeBrightness = \"Last\"\nIdleMode = \"Dim\"");
+        let device = FakeBacklight::new("restore");
+        let mut backlight = BacklightManager::open(device.path.clone());
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), DEFAULT_BRIGHTNESS);
+        device.set(60);
+        idle(&mut backlight, &cfg);
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), DIMMED_BRIGHTNESS);
+        backlight.last_active = Instant::now();
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), 60);
+        // and stays there while awake
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), 60);
+        // until another layer asks for its own
+        backlight.update_backlight(&cfg, Some(200));
+        assert_eq!(backlight.current_bl(), 200);
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), DEFAULT_BRIGHTNESS);
+    }
+
+    #[test]
+    fn max_brightness_wakes_to_the_layer_brightness() {
+        let (cfg, _) = test_bar("IdleMode = \"Dim\"");
+        let device = FakeBacklight::new("max");
+        let mut backlight = BacklightManager::open(device.path.clone());
+        backlight.update_backlight(&cfg, Some(200));
+        device.set(60);
+        idle(&mut backlight, &cfg);
+        backlight.update_backlight(&cfg, Some(200));
+        assert_eq!(backlight.current_bl(), DIMMED_BRIGHTNESS);
+        backlight.last_active = Instant::now();
+        backlight.update_backlight(&cfg, Some(200));
+        assert_eq!(backlight.current_bl(), 200);
+    }
+
+    #[test]
+    fn dimming_waits_for_the_minimum_on_time() {
+        let (cfg, _) = test_bar("BacklightMinOnMs = 5000\nDimTimeoutMs = 1000");
+        let device = FakeBacklight::new("min-on");
+        let mut backlight = BacklightManager::open(device.path.clone());
+        backlight.update_backlight(&cfg, None);
+        idle(&mut backlight, &cfg);
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), DEFAULT_BRIGHTNESS);
+        // wakes up to dim once the minimum on time is over, not only at the next input
+        let timeout = backlight.next_timeout_ms(&cfg);
+        assert!(timeout > 4000 && timeout <= 5001, "{}", timeout);
+        backlight.woke_at = Instant::now() - cfg.backlight_min_on;
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), DIMMED_BRIGHTNESS);
+    }
+
+    #[test]
+    fn disabled_bar_stays_off() {
+        let (cfg, _) = test_bar("AlwaysOn = true");
+        let device = FakeBacklight::new("disabled");
+        let mut backlight = BacklightManager::open(device.path.clone());
+        backlight.set_enabled(false);
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), 0);
+        backlight.set_enabled(true);
+        backlight.update_backlight(&cfg, None);
+        assert_eq!(backlight.current_bl(), DEFAULT_BRIGHTNESS);
+    }
+}
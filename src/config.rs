@@ -0,0 +1,756 @@
+use std::{
+    fs::read_to_string,
+    io::ErrorKind,
+    collections::BTreeSet,
+};
+use anyhow::{anyhow, Error, Result};
+use input_linux::Key;
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{Error as DeError, IntoDeserializer, value::{Error as ValueError, StrDeserializer}}
+};
+use toml::{Table, Value};
+use crate::display::PanelOrientation;
+
+pub const BASE_CFG_PATH: &'static str = "/usr/share/tiny-dfr/config.toml";
+pub const USER_CFG_PATH: &'static str = "/etc/tiny-dfr/config.toml";
+// used when the base config itself can not be loaded
+const EMBEDDED_CFG: &'static str = include_str!("../share/tiny-dfr/config.toml");
+// highest key code accepted by the kernel (KEY_MAX)
+pub const MAX_KEY_CODE: u16 = 0x2FF;
+// keys that are read under another name, and written back under that name
+const KEY_ALIASES: &'static [(&'static str, &'static str)] = &[("Svg", "Icon")];
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConfigProxy {
+    // read by on_config_error before the rest of the file is parsed
+    pub on_config_error: Option<OnConfigError>,
+    pub media_layer_default: Option<bool>,
+    pub layer_preset: Option<LayerPreset>,
+    pub show_button_outlines: Option<bool>,
+    pub enable_pixel_shift: Option<bool>,
+    pub font_template: Option<String>,
+    pub glyph_font_template: Option<String>,
+    pub fallback_font_templates: Option<Vec<String>>,
+    pub fn_mode: Option<FnMode>,
+    pub fn_key: Option<Key>,
+    pub render_quality: Option<RenderQuality>,
+    pub animations: Option<bool>,
+    pub antialiasing: Option<Antialiasing>,
+    pub fn_tap_threshold_ms: Option<u64>,
+    pub icon_offset: Option<f64>,
+    pub layout: Option<Layout>,
+    pub left_inset: Option<f64>,
+    pub right_inset: Option<f64>,
+    pub min_button_width: Option<u32>,
+    pub overflow: Option<Overflow>,
+    pub color_gamma: Option<f64>,
+    pub display_connector: Option<String>,
+    pub backlight_min_on_ms: Option<u64>,
+    pub panel_orientation: Option<PanelOrientation>,
+    pub next_layer_key: Option<Key>,
+    pub previous_layer_key: Option<Key>,
+    pub press_flash_ms: Option<u64>,
+    pub debug_key_overlay: Option<bool>,
+    pub startup_grace_ms: Option<u64>,
+    pub debug_draw_timing: Option<bool>,
+    pub fn_hints_ms: Option<u64>,
+    pub drop_privileges: Option<bool>,
+    pub priv_drop_user: Option<String>,
+    pub priv_drop_groups: Option<Vec<String>>,
+    pub stuck_touch_timeout_ms: Option<u64>,
+    pub resume_redraw_ms: Option<u64>,
+    pub icon_scale: Option<f64>,
+    pub touch_calibration: Option<TouchCalibration>,
+    pub primary_layer_keys: Option<Vec<ButtonConfig>>,
+    pub media_layer_keys: Option<Vec<ButtonConfig>>,
+    pub primary_layer_background: Option<String>,
+    pub media_layer_background: Option<String>,
+    pub primary_layer_brightness: Option<u32>,
+    pub media_layer_brightness: Option<u32>,
+    pub primary_layer_name: Option<String>,
+    pub media_layer_name: Option<String>,
+    pub primary_layer_glyphs: Option<Vec<String>>,
+    pub media_layer_glyphs: Option<Vec<String>>,
+    pub glyphs_per_page: Option<u32>,
+    pub layer_name_overlay_ms: Option<u64>,
+    pub max_fps: Option<u32>,
+    pub dim_timeout_ms: Option<u64>,
+    pub off_timeout_ms: Option<u64>,
+    pub max_brightness: Option<u32>,
+    pub always_on: Option<bool>,
+    pub ac_power: Option<PowerProfile>,
+    pub battery_power: Option<PowerProfile>,
+    pub keyboard_profiles: Option<Vec<KeyboardProfile>>,
+    pub mirror_keyboard: Option<bool>,
+    pub dwell_ms: Option<u64>,
+    pub repeat_start_rate: Option<f64>,
+    pub repeat_end_rate: Option<f64>,
+    pub repeat_ramp_ms: Option<u64>,
+    pub persist_latched: Option<bool>,
+    pub key_modifier: Option<Key>,
+    pub idle_mode: Option<IdleMode>,
+    pub wake_brightness: Option<WakeBrightness>,
+    pub background_action: Option<ActionConfig>
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ButtonConfig {
+    #[serde(alias = "Svg")]
+    pub icon: Option<String>,
+    pub text: Option<String>,
+    pub glyph: Option<String>,
+    pub active_text: Option<String>,
+    pub active_icon: Option<String>,
+    pub icon_offset: Option<f64>,
+    pub cooldown_ms: Option<u64>,
+    pub action: Option<ActionConfig>,
+    pub secondary: Option<Box<ButtonConfig>>,
+    pub frames: Option<Vec<String>>,
+    pub frame_ms: Option<u64>,
+    pub animate: Option<Animation>,
+    pub color: Option<String>,
+    pub active_color: Option<String>,
+    pub text_color: Option<String>,
+    pub latched_color: Option<String>,
+    pub latch: Option<bool>,
+    pub sticky: Option<bool>,
+    pub dwell_ms: Option<u64>,
+    pub emit: Option<EmitMode>,
+    pub persist_latch: Option<bool>,
+    pub modifier: Option<Key>,
+    pub level_source: Option<LevelSource>,
+    pub levels: Option<Vec<LevelIcon>>
+}
+
+#[derive(Clone)]
+pub enum ActionConfig {
+    Key(Key),
+    Layer(LayerAction),
+    Blank,
+    Pointer,
+    // raw evdev key code, for keys without a name
+    Code(u16)
+}
+
+// Written back the way it is read, for --dump-config
+impl Serialize for ActionConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ActionConfig::Key(key) => key.serialize(serializer),
+            ActionConfig::Layer(LayerAction::NextLayer) => serializer.serialize_str("NextLayer"),
+            ActionConfig::Layer(LayerAction::PreviousLayer) => serializer.serialize_str("PreviousLayer"),
+            ActionConfig::Layer(LayerAction::NextPage) => serializer.serialize_str("NextPage"),
+            ActionConfig::Layer(LayerAction::PreviousPage) => serializer.serialize_str("PreviousPage"),
+            ActionConfig::Blank => serializer.serialize_str("Blank"),
+            ActionConfig::Pointer => serializer.serialize_str("Pointer"),
+            ActionConfig::Code(code) => serializer.serialize_u16(*code)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ActionConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ActionConfig, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawAction {
+            Name(String),
+            Code(u16)
+        }
+        match RawAction::deserialize(deserializer)? {
+            RawAction::Code(code) => Ok(ActionConfig::Code(code)),
+            RawAction::Name(name) => match name.as_str() {
+                "NextLayer" => Ok(ActionConfig::Layer(LayerAction::NextLayer)),
+                "PreviousLayer" => Ok(ActionConfig::Layer(LayerAction::PreviousLayer)),
+                "NextPage" => Ok(ActionConfig::Layer(LayerAction::NextPage)),
+                "PreviousPage" => Ok(ActionConfig::Layer(LayerAction::PreviousPage)),
+                "Blank" => Ok(ActionConfig::Blank),
+                "Pointer" => Ok(ActionConfig::Pointer),
+                _ => key_from_str(&name).map(ActionConfig::Key).ok_or_else(|| D::Error::custom(format!(
+                    "unknown action \"{}\", run tiny-dfr --list-keys to see valid actions", name
+                )))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayerAction {
+    NextLayer,
+    PreviousLayer,
+    // turn the pages of the glyph set of the active layer, which stays active
+    NextPage,
+    PreviousPage
+}
+
+impl LayerAction {
+    // Index of the layer to switch to from active_layer, wrapping around
+    pub fn apply(self, active_layer: usize, layer_count: usize) -> usize {
+        match self {
+            LayerAction::NextLayer => (active_layer + 1) % layer_count,
+            LayerAction::PreviousLayer => (active_layer + layer_count - 1) % layer_count,
+            LayerAction::NextPage | LayerAction::PreviousPage => active_layer
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FnMode {
+    // the secondary layer is shown only while Fn is held
+    Hold,
+    // like Hold, but a quick tap latches the secondary layer until Fn is tapped again
+    Hybrid
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Animation {
+    // frames are looped forever
+    Continuous,
+    // frames are played once every time the button fires
+    OnPress,
+    // frames are looped while the button is held or latched
+    WhileActive
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum EmitMode {
+    // the key is held down for as long as the button is touched
+    Hold,
+    // the key is pressed and released right away when the button fires
+    #[serde(alias = "Pulse")]
+    Tap,
+    // like Tap, then tapped again and again while held, faster the longer it is held
+    Repeat
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum IdleMode {
+    // dim after DimTimeoutMs and turn off after OffTimeoutMs
+    Off,
+    // dim after DimTimeoutMs and stay dimmed
+    Dim,
+    // like Dim, but the time is shown instead of the buttons while dimmed
+    Clock
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum WakeBrightness {
+    // the brightness of the layer, capped by MaxBrightness
+    Max,
+    // the brightness the bar had before it dimmed, even if set outside of tiny-dfr
+    Last
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LevelSource {
+    // brightness of the screen backlight
+    Backlight
+}
+
+// Shown while the level source is at most Max percent
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct LevelIcon {
+    pub max: u32,
+    pub icon: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RenderQuality {
+    // MaxFps, Animations and Antialiasing are used as configured
+    Custom,
+    // few redraws, no animations and fast antialiasing, for the least CPU use
+    Performance,
+    // smooth animations and the best antialiasing
+    Quality
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Antialiasing {
+    Fast,
+    Good,
+    Best
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LayerPreset {
+    // the media keys and the F{number} keys are on layers of their own
+    Separate,
+    // the default layer also shows the buttons of the secondary layer after its own
+    Combined
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    // all buttons are kept and get narrower than MinButtonWidth
+    Shrink,
+    // the buttons at the end of the layer that do not fit are left out
+    Drop
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Layout {
+    // buttons are stretched to fill the whole bar
+    Stretch,
+    // buttons are sized to fit the widest content of the layer and centered
+    Compact
+}
+
+// Corrects digitizer coordinates that are offset or scaled compared to the display
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct TouchCalibration {
+    pub x_offset: f64,
+    pub y_offset: f64,
+    pub x_scale: f64,
+    pub y_scale: f64
+}
+
+impl Default for TouchCalibration {
+    fn default() -> TouchCalibration {
+        TouchCalibration { x_offset: 0.0, y_offset: 0.0, x_scale: 1.0, y_scale: 1.0 }
+    }
+}
+
+impl TouchCalibration {
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        (x * self.x_scale + self.x_offset, y * self.y_scale + self.y_offset)
+    }
+}
+
+// Settings that can differ between running on AC and on battery,
+// unset ones fall back to the top level settings of the same name
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct PowerProfile {
+    pub dim_timeout_ms: Option<u64>,
+    pub off_timeout_ms: Option<u64>,
+    pub max_brightness: Option<u32>,
+    pub max_fps: Option<u32>,
+    pub always_on: Option<bool>
+}
+
+impl PowerProfile {
+    pub fn or(self, base: PowerProfile) -> PowerProfile {
+        PowerProfile {
+            dim_timeout_ms: self.dim_timeout_ms.or(base.dim_timeout_ms),
+            off_timeout_ms: self.off_timeout_ms.or(base.off_timeout_ms),
+            max_brightness: self.max_brightness.or(base.max_brightness),
+            max_fps: self.max_fps.or(base.max_fps),
+            always_on: self.always_on.or(base.always_on)
+        }
+    }
+}
+
+// Layer switching keys of keyboards whose name contains Device,
+// unset keys fall back to the top level settings
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct KeyboardProfile {
+    pub device: String,
+    pub fn_key: Option<Key>,
+    pub ignore_fn: Option<bool>,
+    pub next_layer_key: Option<Key>,
+    pub previous_layer_key: Option<Key>
+}
+
+#[derive(Clone, Copy)]
+pub struct Color {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64
+}
+
+impl Color {
+    pub fn gray(level: f64) -> Color {
+        Color { r: level, g: level, b: level, a: 1.0 }
+    }
+    // Accepts #RRGGBB and #RRGGBBAA, colors without alpha are opaque
+    pub fn parse(spec: &str) -> Result<Color> {
+        let invalid = || anyhow!("Invalid color \"{}\", expected #RRGGBB or #RRGGBBAA", spec);
+        let hex = spec.strip_prefix('#').filter(|hex| hex.len() == 6 || hex.len() == 8).ok_or_else(invalid)?;
+        let value = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        let (value, alpha) = if hex.len() == 8 { (value >> 8, value & 0xFF) } else { (value, 0xFF) };
+        let channel = |shift: u32| ((value >> shift) & 0xFF) as f64 / 255.0;
+        Ok(Color { r: channel(16), g: channel(8), b: channel(0), a: alpha as f64 / 255.0 })
+    }
+    // Blends a translucent color over black
+    pub fn opaque(self) -> Color {
+        Color { r: self.r * self.a, g: self.g * self.a, b: self.b * self.a, a: 1.0 }
+    }
+}
+
+pub fn key_from_str(name: &str) -> Option<Key> {
+    let deserializer: StrDeserializer<ValueError> = name.into_deserializer();
+    Key::deserialize(deserializer).ok()
+}
+
+// Names accepted by the Action key of buttons, sorted alphabetically
+pub fn key_names() -> BTreeSet<String> {
+    (0..=MAX_KEY_CODE)
+        .filter_map(|code| Key::from_code(code).ok())
+        .map(|key| format!("{:?}", key))
+        .filter(|name| !name.starts_with("Unknown") && !name.starts_with("Reserved"))
+        .chain(["NextLayer", "PreviousLayer", "NextPage", "PreviousPage", "Blank", "Pointer"].map(String::from))
+        .collect()
+}
+
+impl ConfigProxy {
+    pub fn parse(contents: &str) -> Result<ConfigProxy> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    // Replaces MaxFps, Animations and Antialiasing with what RenderQuality sets them to
+    pub fn apply_render_quality(&mut self) {
+        let (max_fps, animations, antialiasing) = match self.render_quality.unwrap() {
+            RenderQuality::Custom => return,
+            RenderQuality::Performance => (15, false, Antialiasing::Fast),
+            RenderQuality::Quality => (60, true, Antialiasing::Best)
+        };
+        self.max_fps = Some(max_fps);
+        self.animations = Some(animations);
+        self.antialiasing = Some(antialiasing);
+    }
+
+    // Overrides every option that is set in user
+    pub fn merge(&mut self, user: ConfigProxy) {
+        self.on_config_error = user.on_config_error.or(self.on_config_error);
+        self.media_layer_default = user.media_layer_default.or(self.media_layer_default);
+        self.layer_preset = user.layer_preset.or(self.layer_preset);
+        self.show_button_outlines = user.show_button_outlines.or(self.show_button_outlines);
+        self.enable_pixel_shift = user.enable_pixel_shift.or(self.enable_pixel_shift);
+        self.font_template = user.font_template.or(self.font_template.take());
+        self.glyph_font_template = user.glyph_font_template.or(self.glyph_font_template.take());
+        self.fallback_font_templates = user.fallback_font_templates.or(self.fallback_font_templates.take());
+        self.fn_mode = user.fn_mode.or(self.fn_mode);
+        self.fn_key = user.fn_key.or(self.fn_key);
+        self.render_quality = user.render_quality.or(self.render_quality);
+        self.animations = user.animations.or(self.animations);
+        self.antialiasing = user.antialiasing.or(self.antialiasing);
+        self.fn_tap_threshold_ms = user.fn_tap_threshold_ms.or(self.fn_tap_threshold_ms);
+        self.icon_offset = user.icon_offset.or(self.icon_offset);
+        self.layout = user.layout.or(self.layout);
+        self.left_inset = user.left_inset.or(self.left_inset);
+        self.right_inset = user.right_inset.or(self.right_inset);
+        self.min_button_width = user.min_button_width.or(self.min_button_width);
+        self.overflow = user.overflow.or(self.overflow);
+        self.color_gamma = user.color_gamma.or(self.color_gamma);
+        self.backlight_min_on_ms = user.backlight_min_on_ms.or(self.backlight_min_on_ms);
+        self.panel_orientation = user.panel_orientation.or(self.panel_orientation);
+        self.next_layer_key = user.next_layer_key.or(self.next_layer_key);
+        self.previous_layer_key = user.previous_layer_key.or(self.previous_layer_key);
+        self.press_flash_ms = user.press_flash_ms.or(self.press_flash_ms);
+        self.debug_key_overlay = user.debug_key_overlay.or(self.debug_key_overlay);
+        self.startup_grace_ms = user.startup_grace_ms.or(self.startup_grace_ms);
+        self.debug_draw_timing = user.debug_draw_timing.or(self.debug_draw_timing);
+        self.fn_hints_ms = user.fn_hints_ms.or(self.fn_hints_ms);
+        self.drop_privileges = user.drop_privileges.or(self.drop_privileges);
+        self.priv_drop_user = user.priv_drop_user.or(self.priv_drop_user.take());
+        self.priv_drop_groups = user.priv_drop_groups.or(self.priv_drop_groups.take());
+        self.stuck_touch_timeout_ms = user.stuck_touch_timeout_ms.or(self.stuck_touch_timeout_ms);
+        self.resume_redraw_ms = user.resume_redraw_ms.or(self.resume_redraw_ms);
+        self.icon_scale = user.icon_scale.or(self.icon_scale);
+        self.touch_calibration = user.touch_calibration.or(self.touch_calibration);
+        self.media_layer_keys = user.media_layer_keys.or(self.media_layer_keys.take());
+        self.primary_layer_keys = user.primary_layer_keys.or(self.primary_layer_keys.take());
+        self.media_layer_background = user.media_layer_background.or(self.media_layer_background.take());
+        self.primary_layer_background = user.primary_layer_background.or(self.primary_layer_background.take());
+        self.media_layer_brightness = user.media_layer_brightness.or(self.media_layer_brightness);
+        self.primary_layer_brightness = user.primary_layer_brightness.or(self.primary_layer_brightness);
+        self.media_layer_name = user.media_layer_name.or(self.media_layer_name.take());
+        self.primary_layer_glyphs = user.primary_layer_glyphs.or(self.primary_layer_glyphs.take());
+        self.media_layer_glyphs = user.media_layer_glyphs.or(self.media_layer_glyphs.take());
+        self.glyphs_per_page = user.glyphs_per_page.or(self.glyphs_per_page);
+        self.primary_layer_name = user.primary_layer_name.or(self.primary_layer_name.take());
+        self.layer_name_overlay_ms = user.layer_name_overlay_ms.or(self.layer_name_overlay_ms);
+        self.max_fps = user.max_fps.or(self.max_fps);
+        self.dim_timeout_ms = user.dim_timeout_ms.or(self.dim_timeout_ms);
+        self.off_timeout_ms = user.off_timeout_ms.or(self.off_timeout_ms);
+        self.max_brightness = user.max_brightness.or(self.max_brightness);
+        self.always_on = user.always_on.or(self.always_on);
+        self.ac_power = user.ac_power.or(self.ac_power.take());
+        self.battery_power = user.battery_power.or(self.battery_power.take());
+        self.keyboard_profiles = user.keyboard_profiles.or(self.keyboard_profiles.take());
+        self.mirror_keyboard = user.mirror_keyboard.or(self.mirror_keyboard);
+        self.dwell_ms = user.dwell_ms.or(self.dwell_ms);
+        self.repeat_start_rate = user.repeat_start_rate.or(self.repeat_start_rate);
+        self.repeat_end_rate = user.repeat_end_rate.or(self.repeat_end_rate);
+        self.repeat_ramp_ms = user.repeat_ramp_ms.or(self.repeat_ramp_ms);
+        self.persist_latched = user.persist_latched.or(self.persist_latched);
+        self.key_modifier = user.key_modifier.or(self.key_modifier);
+        self.idle_mode = user.idle_mode.or(self.idle_mode);
+        self.wake_brightness = user.wake_brightness.or(self.wake_brightness);
+        self.background_action = user.background_action.or(self.background_action.take());
+        self.display_connector = user.display_connector.or(self.display_connector.take());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum OnConfigError {
+    // refuse to start
+    Fail,
+    // log the error and continue with the default config
+    Defaults
+}
+
+// Looks up OnConfigError without parsing the whole file,
+// so that it still applies when the rest of the file is broken
+fn on_config_error(contents: &str) -> Option<OnConfigError> {
+    contents.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if key.trim() != "OnConfigError" {
+            return None;
+        }
+        match value.split('#').next()?.trim().trim_matches('"') {
+            "Fail" => Some(OnConfigError::Fail),
+            "Defaults" => Some(OnConfigError::Defaults),
+            _ => None
+        }
+    })
+}
+
+// Keys of contents that no option is read from, e.g. typos or keys of another
+// version of tiny-dfr. They are ignored, so they are only worth a warning
+fn unknown_keys(contents: &str) -> Vec<String> {
+    let (Ok(raw), Ok(config)) = (contents.parse::<Table>(), ConfigProxy::parse(contents)) else {
+        return Vec::new();
+    };
+    // every key that was read is written back
+    let Ok(Value::Table(known)) = Value::try_from(config) else {
+        return Vec::new();
+    };
+    let mut unknown = Vec::new();
+    collect_unknown_keys("", &raw, &known, &mut unknown);
+    unknown
+}
+
+fn collect_unknown_keys(prefix: &str, raw: &Table, known: &Table, unknown: &mut Vec<String>) {
+    for (key, value) in raw {
+        let path = format!("{}{}", prefix, key);
+        let name = KEY_ALIASES.iter().find(|(alias, _)| alias == key).map_or(key.as_str(), |(_, name)| name);
+        match (value, known.get(name)) {
+            (_, None) => unknown.push(path),
+            (Value::Table(raw), Some(Value::Table(known))) => {
+                collect_unknown_keys(&format!("{}.", path), raw, known, unknown);
+            },
+            (Value::Array(raw), Some(Value::Array(known))) => {
+                for (i, (raw, known)) in raw.iter().zip(known).enumerate() {
+                    if let (Value::Table(raw), Value::Table(known)) = (raw, known) {
+                        collect_unknown_keys(&format!("{}[{}].", path, i), raw, known, unknown);
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+// Parses a config file, warning about the keys that are ignored
+fn parse_file(path: &str, contents: &str) -> Result<ConfigProxy> {
+    let config = ConfigProxy::parse(contents)?;
+    for key in unknown_keys(contents) {
+        eprintln!("Ignoring unknown key {} in {}", key, path);
+    }
+    Ok(config)
+}
+
+// Reads the defaults and merges the user config over them.
+// What happens when either file can not be loaded is decided by OnConfigError,
+// under Fail the error is returned for the caller to report.
+pub fn read_config() -> Result<ConfigProxy> {
+    let base_contents = read_to_string(BASE_CFG_PATH);
+    let user_contents = match read_to_string(USER_CFG_PATH) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    };
+    let policy = user_contents.as_ref().ok().and_then(|c| c.as_deref()).and_then(on_config_error)
+        .or_else(|| base_contents.as_deref().ok().and_then(on_config_error))
+        .unwrap_or(OnConfigError::Defaults);
+    let report = |path: &str, e: Error| match policy {
+        OnConfigError::Fail => Err(anyhow!("Failed to load {}: {}", path, e)),
+        OnConfigError::Defaults => {
+            eprintln!("Failed to load {}, falling back to the defaults: {}", path, e);
+            Ok(())
+        }
+    };
+
+    let mut base = match base_contents.map_err(Error::from).and_then(|c| parse_file(BASE_CFG_PATH, &c)) {
+        Ok(base) => base,
+        Err(e) => {
+            report(BASE_CFG_PATH, e)?;
+            ConfigProxy::parse(EMBEDDED_CFG).unwrap()
+        }
+    };
+    match user_contents.map_err(Error::from).and_then(|c| c.map(|c| parse_file(USER_CFG_PATH, &c)).transpose()) {
+        Ok(Some(user)) => base.merge(user),
+        Ok(None) => {},
+        Err(e) => report(USER_CFG_PATH, e)?
+    }
+    // the policy that was followed, also when it came from a file that failed to parse
+    base.on_config_error = Some(policy);
+    Ok(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template() -> ConfigProxy {
+        ConfigProxy::parse(EMBEDDED_CFG).unwrap()
+    }
+
+    fn parse_err(contents: &str) -> String {
+        match ConfigProxy::parse(contents) {
+            Ok(_) => panic!("{} was accepted", contents),
+            Err(e) => e.to_string()
+        }
+    }
+
+    #[test]
+    fn template_sets_every_required_option() {
+        let base = template();
+        macro_rules! assert_set {
+            ($($field:ident),*) => {
+                $(assert!(base.$field.is_some(), concat!(stringify!($field), " is not set by the template"));)*
+            };
+        }
+        assert_set!(
+            on_config_error, media_layer_default, layer_preset, show_button_outlines, enable_pixel_shift,
+            font_template, glyph_font_template, fallback_font_templates, fn_mode, fn_key, render_quality,
+            animations, antialiasing, fn_tap_threshold_ms, icon_offset, layout, left_inset, right_inset,
+            min_button_width, overflow, color_gamma, backlight_min_on_ms, panel_orientation, press_flash_ms,
+            debug_key_overlay, startup_grace_ms, debug_draw_timing, fn_hints_ms, drop_privileges,
+            priv_drop_user, priv_drop_groups, stuck_touch_timeout_ms, resume_redraw_ms, icon_scale,
+            touch_calibration, primary_layer_keys, media_layer_keys, primary_layer_background,
+            media_layer_background, layer_name_overlay_ms, max_fps, dim_timeout_ms, off_timeout_ms,
+            always_on, mirror_keyboard, dwell_ms, repeat_start_rate, repeat_end_rate, repeat_ramp_ms,
+            persist_latched, idle_mode, wake_brightness, glyphs_per_page
+        );
+        assert_eq!(base.priv_drop_user.as_deref(), Some("nobody"));
+        assert_eq!(base.priv_drop_groups.unwrap(), ["input", "video"]);
+        assert_eq!(base.primary_layer_keys.unwrap().len(), 12);
+        assert_eq!(base.media_layer_keys.unwrap().len(), 12);
+    }
+
+    #[test]
+    fn user_options_override_the_defaults() {
+        let mut base = template();
+        base.merge(ConfigProxy::parse(r#"
+            MaxFps = 30
+            PrivDropUser = "tiny-dfr"
+            PrimaryLayerKeys = [{ Text = "Esc", Action = "Esc" }]
+            [BatteryPower]
+            MaxFps = 15
+        "#).unwrap());
+        assert_eq!(base.max_fps, Some(30));
+        assert_eq!(base.priv_drop_user.as_deref(), Some("tiny-dfr"));
+        // lists are replaced, not extended
+        let keys = base.primary_layer_keys.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(matches!(keys[0].action, Some(ActionConfig::Key(Key::Esc))));
+        assert_eq!(base.battery_power.unwrap().max_fps, Some(15));
+        // options the user left out keep their defaults
+        assert!(base.fn_mode == Some(FnMode::Hold));
+        assert_eq!(base.priv_drop_groups.unwrap(), ["input", "video"]);
+        assert_eq!(base.media_layer_keys.unwrap().len(), 12);
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored_and_found() {
+        let contents = r#"
+            PrivdropUser = "nobody"
+            MaxFps = 30
+            PrimaryLayerKeys = [{ Text = "F1", Action = "F1" }, { Text = "F2", Acton = "F2" }]
+            TouchCalibration = { XOfset = 1.0 }
+            [AcPower]
+            MaxFPS = 30
+        "#;
+        assert_eq!(ConfigProxy::parse(contents).unwrap().max_fps, Some(30));
+        assert_eq!(unknown_keys(contents), [
+            "AcPower.MaxFPS", "PrimaryLayerKeys[1].Acton", "PrivdropUser", "TouchCalibration.XOfset"
+        ]);
+        // aliases are not unknown
+        let contents = r#"PrimaryLayerKeys = [{ Svg = "search", Action = "Search", Secondary = { Svg = "mute", Action = "Mute" } }]"#;
+        let keys = ConfigProxy::parse(contents).unwrap().primary_layer_keys.unwrap();
+        assert_eq!(keys[0].icon.as_deref(), Some("search"));
+        assert!(unknown_keys(contents).is_empty());
+        assert!(unknown_keys(EMBEDDED_CFG).is_empty());
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        let action = |spec: &str| ConfigProxy::parse(&format!("BackgroundAction = {}", spec))
+            .unwrap().background_action.unwrap();
+        assert!(matches!(action(r#""F5""#), ActionConfig::Key(Key::F5)));
+        assert!(matches!(action(r#""NextLayer""#), ActionConfig::Layer(LayerAction::NextLayer)));
+        assert!(matches!(action(r#""PreviousLayer""#), ActionConfig::Layer(LayerAction::PreviousLayer)));
+        assert!(matches!(action(r#""NextPage""#), ActionConfig::Layer(LayerAction::NextPage)));
+        assert!(matches!(action(r#""Blank""#), ActionConfig::Blank));
+        assert!(matches!(action(r#""Pointer""#), ActionConfig::Pointer));
+        assert!(matches!(action("0x2a0"), ActionConfig::Code(0x2a0)));
+    }
+
+    #[test]
+    fn bad_key_names_are_explained() {
+        let e = parse_err(r#"PrimaryLayerKeys = [{ Text = "F1", Action = "F99" }]"#);
+        assert!(e.contains(r#"unknown action "F99""#), "{}", e);
+        assert!(e.contains("tiny-dfr --list-keys"), "{}", e);
+        parse_err(r#"FnKey = "NotAKey""#);
+        parse_err(r#"PrimaryLayerKeys = [{ Text = "F1", Action = "F1", Modifier = "Shift" }]"#);
+    }
+
+    #[test]
+    fn key_names_are_case_sensitive() {
+        assert_eq!(key_from_str("F1"), Some(Key::F1));
+        assert_eq!(key_from_str("PlayPause"), Some(Key::PlayPause));
+        assert_eq!(key_from_str("f1"), None);
+        // layer actions are not keys
+        assert_eq!(key_from_str("NextLayer"), None);
+        assert!(key_names().contains("NextLayer"));
+        assert!(key_names().contains("F1"));
+    }
+
+    #[test]
+    fn colors_are_parsed() {
+        let color = Color::parse("#ff8000").unwrap();
+        assert_eq!((color.r, color.g, color.b, color.a), (1.0, 128.0 / 255.0, 0.0, 1.0));
+        let color = Color::parse("#00ff0080").unwrap();
+        assert_eq!((color.r, color.g, color.b, color.a), (0.0, 1.0, 0.0, 128.0 / 255.0));
+    }
+
+    #[test]
+    fn malformed_colors_are_explained() {
+        for spec in ["ff8000", "#ff80", "#ff80000", "#ff8000801", "#gg8000", "#ff 800", "#ééé"] {
+            let e = Color::parse(spec).err().unwrap().to_string();
+            assert_eq!(e, format!("Invalid color \"{}\", expected #RRGGBB or #RRGGBBAA", spec));
+        }
+    }
+
+    #[test]
+    fn on_config_error_is_found_in_broken_files() {
+        assert!(on_config_error("OnConfigError = \"Fail\"\nMaxFps = [") == Some(OnConfigError::Fail));
+        assert!(on_config_error("OnConfigError = \"Defaults\" # comment") == Some(OnConfigError::Defaults));
+        assert!(on_config_error("MaxFps = 60").is_none());
+        assert!(on_config_error("OnConfigError = \"Sometimes\"").is_none());
+        assert!(on_config_error("# OnConfigError = \"Fail\"").is_none());
+        assert!(on_config_error(EMBEDDED_CFG) == Some(OnConfigError::Defaults));
+    }
+
+    #[test]
+    fn render_quality_sets_the_values_it_stands_for() {
+        let mut base = template();
+        base.merge(ConfigProxy::parse("RenderQuality = \"Performance\"\nMaxFps = 120").unwrap());
+        base.apply_render_quality();
+        assert_eq!(base.max_fps, Some(15));
+        assert_eq!(base.animations, Some(false));
+        assert!(base.antialiasing == Some(Antialiasing::Fast));
+        let mut base = template();
+        base.merge(ConfigProxy::parse("MaxFps = 120").unwrap());
+        base.apply_render_quality();
+        assert_eq!(base.max_fps, Some(120));
+    }
+}
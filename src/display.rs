@@ -1,5 +1,6 @@
 use std::{
     fs::{File, OpenOptions, self},
+    io,
     os::unix::io::{AsFd, BorrowedFd},
     path::Path,
 };
@@ -11,6 +12,7 @@ use drm::{
     }
 };
 use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
 
 struct Card(File);
 impl AsFd for Card {
@@ -23,18 +25,28 @@ impl ControlDevice for Card {}
 impl DrmDevice for Card {}
 
 impl Card {
-    fn open(path: &Path) -> Self {
+    fn open(path: &Path) -> io::Result<Self> {
         let mut options = OpenOptions::new();
         options.read(true);
         options.write(true);
 
-        Card(options.open(path).unwrap())
+        Ok(Card(options.open(path)?))
     }
 }
 
+// How the panel is mounted relative to the framebuffer, matching the
+// values of the "panel orientation" connector property that apply to
+// a bar whose framebuffer is taller than it is wide
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PanelOrientation {
+    LeftSideUp,
+    RightSideUp
+}
+
 pub struct DrmBackend {
     card: Card,
     mode: Mode,
+    orientation: Option<PanelOrientation>,
     db: DumbBuffer,
     fb: framebuffer::Handle
 }
@@ -62,8 +74,51 @@ fn find_prop_id<T: ResourceHandle>(
     return Err(anyhow!("Property not found"));
 }
 
-fn try_open_card(path: &Path) -> Result<DrmBackend> {
-    let card = Card::open(path);
+fn connector_name(info: &connector::Info) -> String {
+    let interface = match info.interface() {
+        connector::Interface::DisplayPort => "DP",
+        connector::Interface::EmbeddedDisplayPort => "eDP",
+        connector::Interface::HDMIA => "HDMI-A",
+        connector::Interface::HDMIB => "HDMI-B",
+        connector::Interface::LVDS => "LVDS",
+        connector::Interface::DSI => "DSI",
+        connector::Interface::DPI => "DPI",
+        connector::Interface::Virtual => "Virtual",
+        _ => "Unknown"
+    };
+    format!("{}-{}", interface, info.interface_id())
+}
+
+fn panel_orientation(card: &Card, handle: connector::Handle) -> Option<PanelOrientation> {
+    let id = find_prop_id(card, handle, "panel orientation").ok()?;
+    let props = card.get_properties(handle).ok()?;
+    let (ids, values) = props.as_props_and_values();
+    let value = ids.iter().zip(values).find(|(i, _)| **i == id).map(|(_, v)| *v)?;
+    match value {
+        2 => Some(PanelOrientation::LeftSideUp),
+        3 => Some(PanelOrientation::RightSideUp),
+        _ => None
+    }
+}
+
+fn list_card_displays(path: &Path) -> Result<()> {
+    let card = Card::open(path)?;
+    for handle in card.resource_handles()?.connectors() {
+        let info = card.get_connector(*handle, false)?;
+        let size = match info.modes().get(0).map(|m| m.size()) {
+            Some((w, h)) => format!("{}x{}", w, h),
+            None => "no modes".to_string()
+        };
+        println!(
+            "{}: {} ({:?}, {})",
+            path.display(), connector_name(&info), info.state(), size
+        );
+    }
+    Ok(())
+}
+
+fn try_open_card(path: &Path, connector_name_filter: Option<&str>) -> Result<DrmBackend> {
+    let card = Card::open(path)?;
     card.set_client_capability(ClientCapability::UniversalPlanes, true)?;
     card.set_client_capability(ClientCapability::Atomic, true)?;
     card.acquire_master_lock()?;
@@ -81,16 +136,23 @@ fn try_open_card(path: &Path) -> Result<DrmBackend> {
         .flat_map(|crtc| card.get_crtc(*crtc))
         .collect::<Vec<_>>();
 
-    let con = coninfo
-        .iter()
-        .find(|&i| i.state() == connector::State::Connected)
-        .ok_or(anyhow!("No connected connectors found"))?;
+    let con = match connector_name_filter {
+        Some(name) => coninfo
+            .iter()
+            .find(|&i| connector_name(i) == name)
+            .ok_or(anyhow!("Connector {} not found", name))?,
+        None => coninfo
+            .iter()
+            .find(|&i| i.state() == connector::State::Connected)
+            .ok_or(anyhow!("No connected connectors found"))?
+    };
 
     let &mode = con.modes().get(0).ok_or(anyhow!("No modes found"))?;
     let (disp_width, disp_height) = mode.size();
     if disp_height / disp_width < 30 {
         return Err(anyhow!("This does not look like a touchbar"));
     }
+    let orientation = panel_orientation(&card, con.handle());
     let crtc = crtcinfo.get(0).ok_or(anyhow!("No crtcs found"))?;
     let fmt = DrmFourcc::Xrgb8888;
     let db = card.create_dumb_buffer((64, disp_height.into()), fmt, 32)?;
@@ -170,26 +232,46 @@ fn try_open_card(path: &Path) -> Result<DrmBackend> {
     card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req)?;
 
 
-    Ok(DrmBackend { card, mode, db, fb })
+    Ok(DrmBackend { card, mode, orientation, db, fb })
 }
 
 impl DrmBackend {
-    pub fn open_card() -> Result<DrmBackend> {
+    pub fn open_card(connector: Option<&str>) -> Result<DrmBackend> {
         for entry in fs::read_dir("/dev/dri/")? {
             let entry = entry?;
             if !entry.file_name().to_string_lossy().starts_with("card") {
                 continue
             }
-            match try_open_card(&entry.path()) {
+            match try_open_card(&entry.path(), connector) {
                 Ok(card) => return Ok(card),
                 Err(_) => {}
             }
         }
-        Err(anyhow!("No touchbar device found"))
+        match connector {
+            Some(name) => Err(anyhow!("No touchbar device found on connector {}", name)),
+            None => Err(anyhow!("No touchbar device found"))
+        }
+    }
+    pub fn list_displays() -> Result<()> {
+        for entry in fs::read_dir("/dev/dri/")? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with("card") {
+                continue
+            }
+            // a card that can not be listed, e.g. one without KMS, does not hide the others
+            if let Err(e) = list_card_displays(&entry.path()) {
+                eprintln!("{}: {}", entry.path().display(), e);
+            }
+        }
+        Ok(())
     }
     pub fn mode(&self) -> Mode {
         self.mode
     }
+    // Orientation reported by the kernel, if any
+    pub fn orientation(&self) -> Option<PanelOrientation> {
+        self.orientation
+    }
     pub fn fb_info(&self) -> Result<framebuffer::Info> {
         Ok(self.card.get_framebuffer(self.fb)?)
     }
@@ -0,0 +1,217 @@
+// The main loop driven end to end: touches and keys go in the way libinput reports
+// them, the bar is drawn onto a fake framebuffer and the key events are read back
+// from a fake uinput device
+use std::{fs::File, thread};
+use cairo::{ImageSurface, Format};
+use drm::control::ClipRect;
+use input_linux::{uinput::UInputHandle, Key};
+use crate::{
+    Config, State, TouchInput, KeyInput, Outcome, apply_touch, handle_key, update_buttons, draw_frame,
+    overlay::OverlayManager,
+    tests::{test_bar, pixels, FakeUinput, WIDTH, HEIGHT}
+};
+
+const KEYBOARD: &str = "Apple Internal Keyboard / Trackpad";
+
+// What libinput hands the main loop, touch coordinates before calibration
+enum Event {
+    TouchDown { slot: u32, x: f64, y: f64 },
+    TouchMotion { slot: u32, x: f64, y: f64 },
+    TouchUp { slot: u32 },
+    Key { key: Key, pressed: bool }
+}
+
+struct FakeBar {
+    cfg: Config,
+    bar: State,
+    uinput: FakeUinput,
+    overlays: OverlayManager,
+    surface: ImageSurface,
+    // what the display shows, only the clips of a frame are copied to it like DrmBackend::dirty does
+    framebuffer: Vec<u8>,
+    needs_complete_redraw: bool,
+    saved_layer: Option<usize>,
+    blanked: bool
+}
+
+impl FakeBar {
+    fn new(user: &str) -> FakeBar {
+        let (cfg, bar) = test_bar(user);
+        let surface = ImageSurface::create(Format::ARgb32, HEIGHT as i32, WIDTH as i32).unwrap();
+        let framebuffer = vec![0; (surface.stride() * surface.height()) as usize];
+        let mut fake = FakeBar {
+            cfg, bar, surface, framebuffer,
+            uinput: FakeUinput::new(),
+            overlays: OverlayManager::new(),
+            needs_complete_redraw: true,
+            saved_layer: None,
+            blanked: false
+        };
+        fake.step(Vec::new());
+        fake
+    }
+
+    fn apply(&mut self, outcome: Outcome) {
+        if outcome.save_layer {
+            self.saved_layer = Some(self.bar.active_layer);
+        }
+        self.needs_complete_redraw |= outcome.redraw;
+        self.blanked |= outcome.blank;
+    }
+
+    // One turn of the main loop: the events, the timers and then the frame
+    fn step(&mut self, events: Vec<Event>) {
+        for event in events {
+            let outcome = match event {
+                Event::Key { key, pressed } => {
+                    handle_key(&mut self.bar, KeyInput { device: KEYBOARD, key: key as u32, pressed }, &self.cfg)
+                },
+                touch => {
+                    let calibrate = |x, y| self.cfg.touch_calibration.apply(x, y);
+                    let input = match touch {
+                        Event::TouchDown { slot, x, y } => {
+                            let (x, y) = calibrate(x, y);
+                            TouchInput::Down { slot, x, y }
+                        },
+                        Event::TouchMotion { slot, x, y } => {
+                            let (x, y) = calibrate(x, y);
+                            TouchInput::Motion { slot, x, y }
+                        },
+                        Event::TouchUp { slot } => TouchInput::Up { slot },
+                        Event::Key { .. } => unreachable!()
+                    };
+                    apply_touch(
+                        &mut self.bar, input, &self.cfg, WIDTH, HEIGHT, &mut self.uinput.handle,
+                        &mut None::<UInputHandle<File>>, &mut self.overlays
+                    )
+                }
+            };
+            self.apply(outcome);
+        }
+        let (outcome, _) = update_buttons(&mut self.bar, &self.cfg, &mut self.uinput.handle, &mut self.overlays);
+        self.apply(outcome);
+        let overlay = self.overlays.current().map(str::to_string);
+        let clips = draw_frame(
+            &mut self.bar, &self.cfg, overlay.as_deref(), true, WIDTH, HEIGHT, &self.surface, (0.0, 0.0), self.needs_complete_redraw
+        );
+        self.needs_complete_redraw = false;
+        if let Some(clips) = clips {
+            self.copy(&clips);
+        }
+    }
+
+    fn copy(&mut self, clips: &[ClipRect]) {
+        let stride = self.surface.stride() as usize;
+        let data = pixels(&mut self.surface);
+        for clip in clips {
+            for y in clip.y1() as usize..clip.y2() as usize {
+                let row = y * stride;
+                let (start, end) = (row + clip.x1() as usize * 4, row + clip.x2() as usize * 4);
+                self.framebuffer[start..end].copy_from_slice(&data[start..end]);
+            }
+        }
+    }
+
+    // Where a touch lands on button btn of the active layer, in panel coordinates
+    fn button(&self, btn: usize) -> (f64, f64) {
+        let geometry = self.bar.layers[self.bar.active_layer].geometry(&self.cfg, WIDTH as i32);
+        (geometry.left_edge(btn) + geometry.button_width / 2.0, HEIGHT as f64 / 2.0)
+    }
+
+    fn tap(&mut self, btn: usize) {
+        let (x, y) = self.button(btn);
+        self.step(vec![Event::TouchDown { slot: 0, x, y }]);
+        self.step(vec![Event::TouchUp { slot: 0 }]);
+    }
+
+    // Whether the framebuffer shows what a complete redraw would, nothing stale is left
+    fn is_up_to_date(&mut self) -> bool {
+        let mut surface = ImageSurface::create(Format::ARgb32, HEIGHT as i32, WIDTH as i32).unwrap();
+        draw_frame(&mut self.bar, &self.cfg, None, true, WIDTH, HEIGHT, &surface, (0.0, 0.0), true);
+        pixels(&mut surface) == self.framebuffer
+    }
+}
+
+#[test]
+fn touch_types_its_key_and_lights_its_button() {
+    let mut fake = FakeBar::new("PressFlashMs = 0");
+    let idle = fake.framebuffer.clone();
+    assert!(fake.is_up_to_date());
+    let (x, y) = fake.button(1);
+    fake.step(vec![Event::TouchDown { slot: 0, x, y }]);
+    assert_eq!(fake.uinput.keys(), [(Key::F2 as u16, 1)]);
+    assert!(fake.framebuffer != idle);
+    assert!(fake.is_up_to_date());
+    fake.step(vec![Event::TouchMotion { slot: 0, x: x + 5.0, y }, Event::TouchUp { slot: 0 }]);
+    assert_eq!(fake.uinput.keys(), [(Key::F2 as u16, 0)]);
+    assert!(fake.framebuffer == idle);
+}
+
+#[test]
+fn calibration_applies_before_the_touch_is_placed() {
+    // the digitizer reports touches 300 px to the left of where they are
+    let mut fake = FakeBar::new("TouchCalibration = { XOffset = 300.0 }");
+    let (x, y) = fake.button(1);
+    fake.step(vec![Event::TouchDown { slot: 0, x: x - 300.0, y }, Event::TouchUp { slot: 0 }]);
+    assert_eq!(fake.uinput.keys(), [(Key::F2 as u16, 1), (Key::F2 as u16, 0)]);
+}
+
+#[test]
+fn fn_brings_the_media_keys() {
+    let mut fake = FakeBar::new("");
+    let primary = fake.framebuffer.clone();
+    fake.step(vec![Event::Key { key: Key::Fn, pressed: true }]);
+    assert_eq!(fake.bar.active_layer, 1);
+    assert!(fake.framebuffer != primary);
+    assert!(fake.is_up_to_date());
+    fake.tap(0);
+    assert_eq!(fake.uinput.keys(), [(Key::PlayPause as u16, 1), (Key::PlayPause as u16, 0)]);
+    thread::sleep(fake.cfg.fn_tap_threshold);
+    fake.step(vec![Event::Key { key: Key::Fn, pressed: false }]);
+    assert_eq!(fake.saved_layer, Some(0));
+    assert!(fake.framebuffer == primary);
+    // the bar itself never sends Fn
+    assert_eq!(fake.uinput.keys(), []);
+}
+
+#[test]
+fn blank_button_turns_the_bar_off() {
+    let mut fake = FakeBar::new("");
+    fake.tap(3);
+    assert!(fake.blanked);
+    assert_eq!(fake.uinput.keys(), []);
+}
+
+#[test]
+fn layer_button_switches_and_saves_the_layer() {
+    let mut fake = FakeBar::new(r#"
+        PrimaryLayerKeys = [{ Text = "F1", Action = "F1" }, { Text = "More", Action = "NextLayer" }]
+    "#);
+    fake.tap(1);
+    assert_eq!(fake.bar.active_layer, 1);
+    assert_eq!(fake.saved_layer, Some(1));
+    assert!(fake.is_up_to_date());
+    // and its touch does not type anything on the new layer
+    assert_eq!(fake.uinput.keys(), []);
+}
+
+#[test]
+fn page_button_turns_the_glyph_pages() {
+    let mut fake = FakeBar::new(r#"
+        PrimaryLayerKeys = []
+        PrimaryLayerGlyphs = ["a", "b", "c", "d", "e"]
+        GlyphsPerPage = 3
+    "#);
+    let first_page = fake.framebuffer.clone();
+    let geometry = fake.bar.layers[0].geometry(&fake.cfg, WIDTH as i32);
+    // the right half of the page button
+    let (x, y) = (geometry.left_edge(0) + geometry.button_width * 0.75, HEIGHT as f64 / 2.0);
+    fake.step(vec![Event::TouchDown { slot: 0, x, y }, Event::TouchUp { slot: 0 }]);
+    assert_eq!(fake.bar.layers[0].buttons.len(), 3);
+    assert_eq!(fake.saved_layer, None);
+    assert!(fake.framebuffer != first_page);
+    assert!(fake.is_up_to_date());
+    fake.tap(1);
+    // the glyph is typed through the unicode input sequence
+    assert!(!fake.uinput.keys().is_empty());
+}
@@ -0,0 +1,47 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use anyhow::{Result, anyhow};
+use crate::config::LevelSource;
+
+const BACKLIGHT_DIR: &'static str = "/sys/class/backlight";
+// backlights of the touch bar itself, see backlight.rs
+const TOUCH_BAR_BACKLIGHTS: [&'static str; 2] = ["display-pipe", "appletb_backlight"];
+
+fn read_u32(path: &Path) -> Result<u32> {
+    Ok(fs::read_to_string(path)?.trim().parse()?)
+}
+
+// The first backlight that does not belong to the touch bar is taken to be the screen's,
+// e.g. apple-panel-bl on Apple Silicon and gmux_backlight or intel_backlight on Intel Macs
+fn find_screen_backlight() -> Result<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(BACKLIGHT_DIR)?
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !TOUCH_BAR_BACKLIGHTS.iter().any(|s| name.contains(s))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    // read_dir order is arbitrary, keep the pick stable
+    paths.sort();
+    paths.into_iter().next().ok_or_else(|| anyhow!("No screen backlight found"))
+}
+
+fn read_percent(path: &Path) -> Result<u32> {
+    let max = read_u32(&path.join("max_brightness"))?;
+    if max == 0 {
+        return Err(anyhow!("{} has a max_brightness of 0", path.display()));
+    }
+    let value = read_u32(&path.join("brightness"))?;
+    Ok((value.min(max) as u64 * 100 / max as u64) as u32)
+}
+
+// Current value of a level source in percent
+pub fn read_level(source: LevelSource) -> Result<u32> {
+    match source {
+        LevelSource::Backlight => read_percent(&find_screen_backlight()?)
+    }
+}
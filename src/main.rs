@@ -1,97 +1,381 @@
 use std::{
-    fs::{File, OpenOptions, read_to_string},
+    fs::{self, File, OpenOptions},
     os::{
         fd::{AsRawFd, AsFd},
         unix::{io::OwnedFd, fs::OpenOptionsExt}
     },
     path::Path,
-    collections::HashMap,
-    cmp::min,
-    panic::{self, AssertUnwindSafe}
+    collections::{BTreeSet, HashMap},
+    cmp::{min, max},
+    time::{Duration, Instant},
+    panic::{self, AssertUnwindSafe},
+    fmt::Display,
+    io::ErrorKind,
+    thread,
+    env
 };
 use cairo::{ImageSurface, Format, Context, Surface, Rectangle, FontFace, Antialias};
 use rsvg::{Loader, CairoRenderer, SvgHandle};
-use drm::control::ClipRect;
-use anyhow::{Error, Result};
+use gio::{MemoryInputStream, glib::Bytes};
+use data_url::DataUrl;
+use drm::control::{ClipRect, framebuffer};
+use anyhow::{anyhow, Result};
 use input::{
     Libinput, LibinputInterface, Device as InputDevice,
     event::{
         Event, device::DeviceEvent, EventTrait,
         touch::{TouchEvent, TouchEventPosition, TouchEventSlot},
-        keyboard::{KeyboardEvent, KeyboardEventTrait, KeyState}
+        keyboard::{KeyboardEvent, KeyboardEventTrait, KeyState},
+        switch::{Switch, SwitchEvent, SwitchState}
     }
 };
 use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY, c_char};
-use input_linux::{uinput::UInputHandle, EventKind, Key, SynchronizeKind};
-use input_linux_sys::{uinput_setup, input_id, timeval, input_event};
+use input_linux::{uinput::UInputHandle, EventKind, Key, SynchronizeKind, AbsoluteAxis};
+use input_linux_sys::{uinput_setup, uinput_abs_setup, input_id, input_absinfo, timeval, input_event};
 use nix::{
     poll::{poll, PollFd, PollFlags},
     sys::{
         signal::{Signal, SigSet},
+        signalfd::{SignalFd, SfdFlags},
         inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor}
     },
+    unistd::{User, Group},
+    time::{clock_gettime, ClockId},
     errno::Errno
 };
 use privdrop::PrivDrop;
-use serde::Deserialize;
-use freetype::Library as FtLibrary;
+use freetype::{Library as FtLibrary, Face as FtFace};
 
 mod backlight;
+mod config;
 mod display;
 mod pixel_shift;
 mod fonts;
+mod overlay;
+mod power;
+mod state;
+mod level;
+#[cfg(all(test, feature = "integration-tests"))]
+mod integration_tests;
 
 use backlight::BacklightManager;
-use display::DrmBackend;
+use config::{
+    ConfigProxy, ButtonConfig, ActionConfig, LayerAction, LayerPreset, Overflow, FnMode, IdleMode, WakeBrightness, Layout, Color, Animation, EmitMode, LevelSource, Antialiasing, TouchCalibration, PowerProfile, KeyboardProfile, USER_CFG_PATH, MAX_KEY_CODE,
+    read_config, key_names
+};
+use display::{DrmBackend, PanelOrientation};
 use pixel_shift::{PixelShiftManager, PIXEL_SHIFT_WIDTH_PX};
 use fonts::{FontConfig, Pattern};
+use overlay::{OverlayManager, OverlayPriority};
+use power::PowerManager;
+use state::{StateManager, ButtonId};
+use level::read_level;
 
 const BUTTON_SPACING_PX: i32 = 16;
+const COMPACT_BUTTON_SPACING_PX: i32 = 48;
+const COMPACT_BUTTON_PADDING_PX: f64 = 24.0;
 const BUTTON_COLOR_INACTIVE: f64 = 0.200;
 const BUTTON_COLOR_ACTIVE: f64 = 0.400;
+const BUTTON_COLOR_DIVIDER: f64 = 0.600;
 const ICON_SIZE: i32 = 48;
+const CAPTION_SPACING_PX: f64 = 8.0;
+const DEFAULT_FRAME_MS: u64 = 100;
+const FONT_SIZE: f64 = 32.0;
+const HINT_FONT_SIZE: f64 = 14.0;
 const TIMEOUT_MS: i32 = 10 * 1000;
-const USER_CFG_PATH: &'static str = "/etc/tiny-dfr/config.toml";
+const DEBUG_KEY_OVERLAY_MS: u64 = 500;
+// how long to wait for /dev/uinput to appear, in case the module is still loading
+const UINPUT_WAIT_MS: u64 = 5000;
+const STARTUP_RETRY_INTERVAL_MS: u64 = 500;
+const RESUME_REDRAW_INTERVAL_MS: i32 = 250;
+// taps per second, keeps a zero or negative RepeatStartRate from stalling repeats
+const MIN_REPEAT_RATE: f64 = 0.1;
+// opacity of the contents of buttons whose data source failed
+const UNAVAILABLE_ALPHA: f64 = 0.4;
+// sysfs attributes can not be watched, so level sources are polled
+const LEVEL_POLL_INTERVAL_MS: i32 = 500;
+const DWELL_REDRAW_INTERVAL_MS: i32 = 16;
+const VIRTUAL_DEVICE_NAME: &'static str = "Dynamic Function Row Virtual Input Device";
+const VIRTUAL_POINTER_NAME: &'static str = "Dynamic Function Row Virtual Pointer";
+// absolute pointer positions range from 0 to this, spanning the whole screen
+const POINTER_ABS_MAX: i32 = 0xFFFF;
+const ICON_DIRS: [&'static str; 2] = ["/etc/tiny-dfr", "/usr/share/tiny-dfr"];
 
-#[derive(Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct ConfigProxy {
-    media_layer_default: Option<bool>,
-    show_button_outlines: Option<bool>,
-    enable_pixel_shift: Option<bool>,
-    font_template: Option<String>,
-    primary_layer_keys: Option<Vec<ButtonConfig>>,
-    media_layer_keys: Option<Vec<ButtonConfig>>
+enum Background {
+    Color(Color),
+    // pre-rendered at the size of the panel
+    Image(ImageSurface)
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct ButtonConfig {
-    #[serde(alias = "Svg")]
-    icon: Option<String>,
-    text: Option<String>,
-    action: Key
+impl Background {
+    fn load(spec: &str, width: i32, height: i32) -> Background {
+        if spec.starts_with('#') {
+            // there is nothing behind the background to show through
+            return Background::Color(Color::parse(spec).unwrap().opaque());
+        }
+        let image = load_background_image(spec, width, height)
+            .unwrap_or_else(|e| panic!("Failed to load background {}: {}", spec, e));
+        Background::Image(image)
+    }
+    fn set_source(&self, c: &Context, config: &Config) {
+        match self {
+            Background::Color(color) => config.set_source_color(c, *color),
+            Background::Image(surf) => c.set_source_surface(surf, 0.0, 0.0).unwrap()
+        }
+    }
 }
 
 struct Config {
     show_button_outlines: bool,
     enable_pixel_shift: bool,
-    font_face: FontFace,
+    font: Font,
+    // used for labels with characters that font does not have
+    fallback_fonts: Vec<Font>,
+    glyph_font_face: FontFace,
+    fn_mode: FnMode,
+    // the key treated as Fn on keyboards without a profile
+    fn_key: Key,
+    fn_tap_threshold: Duration,
+    // from RenderQuality or the individual settings
+    animations: bool,
+    antialias: Antialias,
+    icon_offset: f64,
+    layout: Layout,
+    // space kept free at either end of the bar, see inset_px
+    left_inset: f64,
+    right_inset: f64,
+    // buttons are not made narrower than this, see Overflow
+    min_button_width: u32,
+    overflow: Overflow,
+    color_gamma: f64,
+    backlight_min_on: Duration,
+    panel_orientation: PanelOrientation,
+    next_layer_key: Option<Key>,
+    previous_layer_key: Option<Key>,
+    mirror_keyboard: bool,
+    keyboard_profiles: Vec<KeyboardProfile>,
+    press_flash: Duration,
+    persist_latched: bool,
+    key_modifier: Option<Key>,
+    idle_mode: IdleMode,
+    repeat_start_rate: f64,
+    repeat_end_rate: f64,
+    repeat_ramp: Duration,
+    wake_brightness: WakeBrightness,
+    // run by taps that hit no button
+    background_action: Option<ButtonAction>,
+    dwell: Duration,
+    startup_grace: Duration,
+    debug_draw_timing: bool,
+    fn_hints: Duration,
+    layer_name_overlay: Duration,
+    // the settings of ac_power or battery_power, depending on the power state
+    power: PowerSettings,
+    ac_power: PowerSettings,
+    battery_power: PowerSettings,
+    drop_privileges: bool,
+    priv_drop_user: String,
+    priv_drop_groups: Vec<String>,
+    stuck_touch_timeout: Duration,
+    resume_redraw: Duration,
+    // ICON_SIZE scaled by IconScale
+    icon_size: f64,
+    touch_calibration: TouchCalibration,
+    debug_key_overlay: bool,
+}
+
+// Keys that switch layers when pressed on a given keyboard
+struct LayerKeys {
+    fn_key: Option<Key>,
+    next_layer_key: Option<Key>,
+    previous_layer_key: Option<Key>
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct PowerSettings {
+    dim_timeout: Duration,
+    off_timeout: Duration,
+    max_brightness: Option<u32>,
+    // shortest time between two animation updates, from MaxFps
+    min_frame_interval: Duration,
+    always_on: bool
+}
+
+impl PowerSettings {
+    fn from_profile(profile: PowerProfile) -> PowerSettings {
+        PowerSettings {
+            dim_timeout: Duration::from_millis(profile.dim_timeout_ms.unwrap()),
+            off_timeout: Duration::from_millis(profile.off_timeout_ms.unwrap()),
+            max_brightness: profile.max_brightness,
+            min_frame_interval: match profile.max_fps.unwrap() {
+                0 => Duration::ZERO,
+                fps => Duration::from_secs(1) / fps
+            },
+            always_on: profile.always_on.unwrap()
+        }
+    }
+}
+
+impl Config {
+    // Labels are drawn with the first font that has all of their characters
+    fn font_for(&self, text: &str) -> &FontFace {
+        if text.is_ascii() || self.font.has_chars(text) {
+            return &self.font.face;
+        }
+        self.fallback_fonts.iter()
+            .find(|font| font.has_chars(text))
+            .map_or(&self.font.face, |font| &font.face)
+    }
+    fn layer_keys(&self, device_name: &str) -> LayerKeys {
+        let profile = self.keyboard_profiles.iter().find(|p| device_name.contains(&p.device));
+        LayerKeys {
+            fn_key: match profile {
+                Some(p) if p.ignore_fn == Some(true) => None,
+                Some(p) => Some(p.fn_key.unwrap_or(self.fn_key)),
+                None => Some(self.fn_key)
+            },
+            next_layer_key: profile.and_then(|p| p.next_layer_key).or(self.next_layer_key),
+            previous_layer_key: profile.and_then(|p| p.previous_layer_key).or(self.previous_layer_key)
+        }
+    }
+    // Time between the taps of a Repeat button that has been held for held,
+    // going from RepeatStartRate to RepeatEndRate over RepeatRampMs
+    fn repeat_interval(&self, held: Duration) -> Duration {
+        let ramp = if self.repeat_ramp.is_zero() {
+            1.0
+        } else {
+            (held.as_secs_f64() / self.repeat_ramp.as_secs_f64()).min(1.0)
+        };
+        let rate = self.repeat_start_rate + (self.repeat_end_rate - self.repeat_start_rate) * ramp;
+        Duration::from_secs_f64(1.0 / rate.max(MIN_REPEAT_RATE))
+    }
+    fn set_source_color(&self, c: &Context, color: Color) {
+        let correct = |value: f64| value.powf(1.0 / self.color_gamma);
+        c.set_source_rgba(correct(color.r), correct(color.g), correct(color.b), color.a);
+    }
 }
 
 enum ButtonImage {
     Text(String),
+    Glyph(String),
     Svg(SvgHandle),
     Bitmap(ImageSurface)
 }
 
+enum ButtonAction {
+    Key(Key),
+    Glyph(String),
+    Layer(LayerAction),
+    // turns the backlight off until the bar is touched again
+    Blank,
+    // moves the cursor to the position touched within the button
+    Pointer
+}
+
+impl ButtonAction {
+    fn name(&self) -> String {
+        match self {
+            ButtonAction::Key(key) => format!("{:?}", key),
+            ButtonAction::Glyph(glyph) => format!("Glyph {}", glyph),
+            ButtonAction::Layer(layer) => format!("{:?}", layer),
+            ButtonAction::Blank => "Blank".to_string(),
+            ButtonAction::Pointer => "Pointer".to_string()
+        }
+    }
+}
+
+impl From<ActionConfig> for ButtonAction {
+    fn from(action: ActionConfig) -> ButtonAction {
+        match action {
+            ActionConfig::Key(key) => ButtonAction::Key(key),
+            ActionConfig::Layer(layer) => ButtonAction::Layer(layer),
+            ActionConfig::Blank => ButtonAction::Blank,
+            ActionConfig::Pointer => ButtonAction::Pointer,
+            ActionConfig::Code(code) => {
+                let key = Some(code)
+                    .filter(|code| *code > 0 && *code <= MAX_KEY_CODE)
+                    .and_then(|code| Key::from_code(code).ok())
+                    .unwrap_or_else(|| panic!("Invalid config, {:#x} is not a valid key code", code));
+                ButtonAction::Key(key)
+            }
+        }
+    }
+}
+
 struct Button {
     image: ButtonImage,
+    // shown instead of image while the button is pressed
+    active_image: Option<ButtonImage>,
     changed: bool,
     active: bool,
-    action: Key
+    icon_offset: Option<f64>,
+    // presses within this long after the button last fired are ignored
+    cooldown: Duration,
+    last_fired: Option<Instant>,
+    // when the button was last released, it stays highlighted for PressFlashMs after that
+    released_at: Option<Instant>,
+    // split buttons fire this button when their right half is touched
+    secondary: Option<Box<Button>>,
+    // text shown next to the icon of icon buttons
+    caption: Option<String>,
+    // override the default button colors
+    color: Option<Color>,
+    active_color: Option<Color>,
+    // used instead of active_color while the button is latched but not touched
+    latched_color: Option<Color>,
+    text_color: Option<Color>,
+    // touching the button toggles it on and off instead of holding it while touched
+    latching: bool,
+    latched: bool,
+    // a latching button that is released again once another button fires
+    sticky: bool,
+    // the latched state is kept across restarts of the daemon
+    persist_latch: bool,
+    // held down while the key of the button is sent
+    modifier: Option<Key>,
+    // number of touches holding the button, it stays active until all of them lift
+    presses: usize,
+    // how long a touch has to stay on the button before it fires
+    dwell: Duration,
+    dwell_started: Option<Instant>,
+    // animation frames shown instead of image
+    frames: Vec<ButtonImage>,
+    frame_duration: Duration,
+    animation: Animation,
+    emit_mode: EmitMode,
+    // when a held Repeat button started repeating and when it is tapped next
+    repeat_since: Option<Instant>,
+    repeat_at: Option<Instant>,
+    animation_started: Option<Instant>,
+    frame: Option<usize>,
+    // icons shown instead of image depending on the value of level_source,
+    // sorted by the highest value in percent each is shown for
+    level_source: Option<LevelSource>,
+    levels: Vec<(u32, ButtonImage)>,
+    level: Option<usize>,
+    level_checked_at: Option<Instant>,
+    // the level source failed to read, the button is shown as unavailable
+    level_failed: bool,
+    action: ButtonAction
 }
 
+// Toggles do the same thing however long they are held, so they are tapped
+// to keep them from auto repeating, everything else acts like a key
+fn default_emit_mode(action: &ButtonAction) -> EmitMode {
+    match action {
+        ButtonAction::Key(Key::PlayPause | Key::Mute | Key::MicMute) => EmitMode::Tap,
+        _ => EmitMode::Hold
+    }
+}
+
+// Keys used to type a unicode code point through the ctrl+shift+u
+// input method sequence understood by GTK and IBus
+const GLYPH_KEYS: [Key; 20] = [
+    Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7,
+    Key::Num8, Key::Num9, Key::A, Key::B, Key::C, Key::D, Key::E, Key::F,
+    Key::LeftCtrl, Key::LeftShift, Key::U, Key::Space
+];
+
 fn try_load_svg(path: &str) -> Result<ButtonImage> {
     let handle = Loader::new().read_path(format!("/etc/tiny-dfr/{}.svg", path)).or_else(|_| {
         Loader::new().read_path(format!("/usr/share/tiny-dfr/{}.svg", path))
@@ -99,142 +383,809 @@ fn try_load_svg(path: &str) -> Result<ButtonImage> {
     Ok(ButtonImage::Svg(handle))
 }
 
-fn try_load_png(path: &str) -> Result<ButtonImage> {
-    let mut file = File::open(format!("/etc/tiny-dfr/{}.png", path)).or_else(|_| {
-        File::open(format!("/usr/share/tiny-dfr/{}.png", path))
-    })?;
-    let surf = ImageSurface::create_from_png(&mut file)?;
-    if surf.height() == ICON_SIZE && surf.width() == ICON_SIZE {
-        return Ok(ButtonImage::Bitmap(surf));
+// size is the size the icon is drawn at, used to pick between png icons of several sizes
+fn load_icon(icon: &str, size: i32) -> Result<ButtonImage> {
+    if let Some(image) = try_load_inline_svg(icon) {
+        return image;
+    }
+    try_load_svg(icon).or_else(|_| try_load_png(icon, size))
+}
+
+// Icons can also be given inline as svg markup or as a data: URI,
+// returns None if the icon is a file name instead
+fn try_load_inline_svg(icon: &str) -> Option<Result<ButtonImage>> {
+    let data = if icon.trim_start().starts_with('<') {
+        Ok(icon.as_bytes().to_vec())
+    } else if icon.starts_with("data:") {
+        DataUrl::process(icon)
+            .map_err(|e| anyhow!("Invalid data URI: {:?}", e))
+            .and_then(|url| url.decode_to_vec().map_err(|e| anyhow!("Invalid data URI: {:?}", e)))
+            .map(|(data, _)| data)
+    } else {
+        return None;
+    };
+    Some(data.and_then(|data| {
+        let stream = MemoryInputStream::from_bytes(&Bytes::from_owned(data));
+        let handle = Loader::new().read_stream(&stream, None::<&gio::File>, None::<&gio::Cancellable>)?;
+        Ok(ButtonImage::Svg(handle))
+    }))
+}
+
+// Loads every size of a png icon, name.png as well as name@{size}.png,
+// from the first icon directory that has any
+fn load_png_sizes(name: &str) -> Result<Vec<ImageSurface>> {
+    for dir in ICON_DIRS {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue
+        };
+        let mut sizes = Vec::new();
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let Some(stem) = file_name.to_str().and_then(|f| f.strip_suffix(".png")) else {
+                continue
+            };
+            let is_size = stem.strip_prefix(name)
+                .map_or(false, |suffix| suffix.is_empty() || suffix.strip_prefix('@').map_or(false, |size| size.parse::<u32>().is_ok()));
+            if is_size {
+                sizes.push(ImageSurface::create_from_png(&mut File::open(entry.path())?)?);
+            }
+        }
+        if !sizes.is_empty() {
+            return Ok(sizes);
+        }
+    }
+    Err(anyhow!("No icon named {}", name))
+}
+
+fn try_load_png(name: &str, size: i32) -> Result<ButtonImage> {
+    let sizes = load_png_sizes(name)?;
+    // prefer scaling down the smallest large enough image, it stays sharper than scaling up
+    let surf = sizes.iter()
+        .filter(|surf| surf.width() >= size)
+        .min_by_key(|surf| surf.width())
+        .or_else(|| sizes.iter().max_by_key(|surf| surf.width()))
+        .unwrap();
+    if surf.height() == size && surf.width() == size {
+        return Ok(ButtonImage::Bitmap(surf.clone()));
     }
-    let resized = ImageSurface::create(Format::ARgb32, ICON_SIZE, ICON_SIZE).unwrap();
+    let resized = ImageSurface::create(Format::ARgb32, size, size).unwrap();
     let c = Context::new(&resized).unwrap();
-    c.scale(ICON_SIZE as f64 / surf.width() as f64, ICON_SIZE as f64 / surf.height() as f64);
+    c.scale(size as f64 / surf.width() as f64, size as f64 / surf.height() as f64);
     c.set_source_surface(surf, 0.0, 0.0).unwrap();
     c.set_antialias(Antialias::Best);
     c.paint().unwrap();
     return Ok(ButtonImage::Bitmap(resized));
 }
 
+// Renders an image scaled to cover the whole panel, cropping the excess
+fn load_background_image(name: &str, width: i32, height: i32) -> Result<ImageSurface> {
+    let surf = ImageSurface::create(Format::ARgb32, width, height)?;
+    let c = Context::new(&surf)?;
+    let cover = |image_width: f64, image_height: f64| {
+        let scale = (width as f64 / image_width).max(height as f64 / image_height);
+        let (scaled_width, scaled_height) = (image_width * scale, image_height * scale);
+        Rectangle::new(
+            (width as f64 - scaled_width) / 2.0,
+            (height as f64 - scaled_height) / 2.0,
+            scaled_width,
+            scaled_height
+        )
+    };
+    if let Ok(ButtonImage::Svg(svg)) = try_load_svg(name) {
+        let renderer = CairoRenderer::new(&svg);
+        let viewport = match renderer.intrinsic_size_in_pixels() {
+            Some((svg_width, svg_height)) => cover(svg_width, svg_height),
+            None => Rectangle::new(0.0, 0.0, width as f64, height as f64)
+        };
+        renderer.render_document(&c, &viewport)?;
+    } else {
+        let mut file = File::open(format!("/etc/tiny-dfr/{}.png", name)).or_else(|_| {
+            File::open(format!("/usr/share/tiny-dfr/{}.png", name))
+        })?;
+        let png = ImageSurface::create_from_png(&mut file)?;
+        let viewport = cover(png.width() as f64, png.height() as f64);
+        c.translate(viewport.x(), viewport.y());
+        c.scale(viewport.width() / png.width() as f64, viewport.height() / png.height() as f64);
+        c.set_source_surface(png, 0.0, 0.0)?;
+        c.set_antialias(Antialias::Best);
+        c.paint()?;
+    }
+    drop(c);
+    Ok(surf)
+}
+
+impl ButtonImage {
+    fn width(&self, c: &Context, config: &Config) -> f64 {
+        match self {
+            ButtonImage::Text(text) => {
+                c.save().unwrap();
+                c.set_font_face(config.font_for(text));
+                let width = c.text_extents(text).unwrap().width();
+                c.restore().unwrap();
+                width
+            },
+            ButtonImage::Glyph(glyph) => {
+                c.save().unwrap();
+                c.set_font_face(&config.glyph_font_face);
+                let width = c.text_extents(glyph).unwrap().width();
+                c.restore().unwrap();
+                width
+            },
+            ButtonImage::Svg(_) | ButtonImage::Bitmap(_) => config.icon_size
+        }
+    }
+}
+
 impl Button {
-    fn with_config(cfg: ButtonConfig) -> Button {
-        if let Some(text) = cfg.text {
-            Button::new_text(text, cfg.action)
-        } else if let Some(icon) = cfg.icon {
-            Button::new_icon(&icon, cfg.action)
+    fn with_config(cfg: ButtonConfig, config: &Config) -> Button {
+        let mut button = if let Some(glyph) = cfg.glyph {
+            Button::new_glyph(glyph)
         } else {
-            panic!("Invalid config, a button must have either Text or Icon")
+            let action = cfg.action.expect(
+                "Invalid config, a button must have an Action unless it is a Glyph, run tiny-dfr --list-keys to see valid actions"
+            ).into();
+            if let Some(icon) = cfg.icon {
+                let mut button = Button::new_icon(&icon, config, action);
+                button.caption = cfg.text;
+                button
+            } else if let Some(text) = cfg.text {
+                Button::new_text(text, action)
+            } else {
+                panic!("Invalid config, a button must have either Text, Icon or Glyph")
+            }
+        };
+        button.icon_offset = cfg.icon_offset;
+        button.cooldown = Duration::from_millis(cfg.cooldown_ms.unwrap_or(0));
+        button.dwell = cfg.dwell_ms.map_or(config.dwell, Duration::from_millis);
+        button.emit_mode = cfg.emit.unwrap_or_else(|| default_emit_mode(&button.action));
+        button.active_image = cfg.active_text.map(ButtonImage::Text)
+            .or_else(|| cfg.active_icon.map(|icon| load_icon(&icon, config.icon_size.round() as i32).unwrap_or_else(|e| {
+                panic!("Invalid config, failed to load the ActiveIcon of the {} button: {}", button.action.name(), e)
+            })));
+        let name = button.action.name();
+        let parse_color = |spec: String| Color::parse(&spec).unwrap_or_else(|e| {
+            panic!("Invalid config, bad color for the {} button: {}", name, e)
+        });
+        button.color = cfg.color.map(parse_color);
+        button.active_color = cfg.active_color.map(parse_color);
+        button.text_color = cfg.text_color.map(parse_color);
+        button.latched_color = cfg.latched_color.map(parse_color);
+        button.sticky = cfg.sticky.unwrap_or(false);
+        button.latching = cfg.latch.unwrap_or(false) || button.sticky;
+        button.persist_latch = cfg.persist_latch.unwrap_or(config.persist_latched);
+        button.modifier = cfg.modifier.or(config.key_modifier);
+        if let Some(frames) = cfg.frames.filter(|_| config.animations) {
+            button.frames = frames.iter().map(|frame| load_icon(frame, config.icon_size.round() as i32).unwrap_or_else(|e| {
+                panic!("Invalid config, failed to load the animation frame {} of the {} button: {}", frame, button.action.name(), e)
+            })).collect();
+            button.frame_duration = Duration::from_millis(cfg.frame_ms.unwrap_or(DEFAULT_FRAME_MS));
+            button.animation = cfg.animate.unwrap_or(Animation::Continuous);
+            if button.animation == Animation::Continuous && !button.frames.is_empty() {
+                button.animation_started = Some(Instant::now());
+            }
         }
+        if let Some(source) = cfg.level_source {
+            let levels = cfg.levels.filter(|levels| !levels.is_empty()).unwrap_or_else(|| {
+                panic!("Invalid config, the {} button has a LevelSource but no Levels", button.action.name())
+            });
+            button.levels = levels.into_iter().map(|level| {
+                let image = load_icon(&level.icon, config.icon_size.round() as i32).unwrap_or_else(|e| {
+                    panic!("Invalid config, failed to load the level icon {} of the {} button: {}", level.icon, button.action.name(), e)
+                });
+                (level.max, image)
+            }).collect();
+            button.levels.sort_by_key(|(max, _)| *max);
+            button.level_source = Some(source);
+        }
+        button.secondary = cfg.secondary.map(|secondary| {
+            if secondary.secondary.is_some() {
+                panic!("Invalid config, a Secondary button can not be split again");
+            }
+            Box::new(Button::with_config(*secondary, config))
+        });
+        button
     }
-    fn new_text(text: String, action: Key) -> Button {
+    fn new(image: ButtonImage, action: ButtonAction) -> Button {
         Button {
-            action,
+            image, action,
+            active_image: None,
             active: false,
             changed: false,
-            image: ButtonImage::Text(text)
+            icon_offset: None,
+            cooldown: Duration::ZERO,
+            last_fired: None,
+            released_at: None,
+            secondary: None,
+            caption: None,
+            color: None,
+            active_color: None,
+            latched_color: None,
+            text_color: None,
+            latching: false,
+            latched: false,
+            sticky: false,
+            persist_latch: false,
+            modifier: None,
+            presses: 0,
+            dwell: Duration::ZERO,
+            dwell_started: None,
+            frames: Vec::new(),
+            frame_duration: Duration::ZERO,
+            animation: Animation::Continuous,
+            emit_mode: EmitMode::Hold,
+            repeat_since: None,
+            repeat_at: None,
+            animation_started: None,
+            frame: None,
+            level_source: None,
+            levels: Vec::new(),
+            level: None,
+            level_checked_at: None,
+            level_failed: false
         }
     }
-    fn new_icon(path: &str, action: Key) -> Button {
-        let image = try_load_svg(path).or_else(|_| try_load_png(path)).unwrap();
-        Button {
-            action, image,
-            active: false,
-            changed: false,
+    fn new_text(text: String, action: ButtonAction) -> Button {
+        Button::new(ButtonImage::Text(text), action)
+    }
+    fn new_glyph(glyph: String) -> Button {
+        Button::new(ButtonImage::Glyph(glyph.clone()), ButtonAction::Glyph(glyph))
+    }
+    fn new_icon(icon: &str, config: &Config, action: ButtonAction) -> Button {
+        let image = load_icon(icon, config.icon_size.round() as i32).unwrap_or_else(|e| {
+            panic!("Invalid config, failed to load the Icon of the {} button: {}", action.name(), e)
+        });
+        Button::new(image, action)
+    }
+    fn keys(&self) -> Vec<Key> {
+        let mut keys = match &self.action {
+            ButtonAction::Key(key) => [Some(*key), self.modifier].into_iter().flatten().collect(),
+            ButtonAction::Glyph(_) => GLYPH_KEYS.to_vec(),
+            ButtonAction::Layer(_) | ButtonAction::Blank | ButtonAction::Pointer => Vec::new()
+        };
+        if let Some(secondary) = &self.secondary {
+            keys.extend(secondary.keys());
+        }
+        keys
+    }
+    fn content_width(&self, c: &Context, config: &Config) -> f64 {
+        let mut width = self.image.width(c, config);
+        if let ButtonImage::Svg(_) | ButtonImage::Bitmap(_) = self.image {
+            width += self.caption_width(c);
+        }
+        if let Some(image) = &self.active_image {
+            width = width.max(image.width(c, config));
+        }
+        for (_, image) in &self.levels {
+            width = width.max(image.width(c, config));
+        }
+        match &self.secondary {
+            // both halves get the same width
+            Some(secondary) => width.max(secondary.content_width(c, config)) * 2.0,
+            None => width
+        }
+    }
+    fn needs_redraw(&self) -> bool {
+        self.changed || self.secondary.as_ref().map_or(false, |s| s.changed)
+    }
+    // The part of a split button that was touched
+    fn part_mut(&mut self, secondary: bool) -> &mut Button {
+        if secondary && self.secondary.is_some() {
+            self.secondary.as_deref_mut().unwrap()
+        } else {
+            self
         }
     }
-    fn render(&self, c: &Context, height: i32, button_left_edge: f64, button_width: u64, y_shift: f64) {
+    // Short description of the button, used for Fn hints
+    fn label(&self) -> String {
         match &self.image {
+            ButtonImage::Text(text) | ButtonImage::Glyph(text) => text.clone(),
+            ButtonImage::Svg(_) | ButtonImage::Bitmap(_) => self.action.name()
+        }
+    }
+    // Buttons showing data they failed to get are drawn faded
+    fn unavailable(&self) -> bool {
+        self.level_failed
+    }
+    fn highlighted(&self) -> bool {
+        (self.active && self.dwell_started.is_none()) || self.latched || self.released_at.is_some()
+    }
+    fn highlight_color(&self) -> Color {
+        let active_color = self.active_color.unwrap_or(Color::gray(BUTTON_COLOR_ACTIVE));
+        if self.latched && !self.active {
+            self.latched_color.unwrap_or(active_color)
+        } else {
+            active_color
+        }
+    }
+    fn current_image(&self) -> &ButtonImage {
+        match (&self.active_image, self.frame) {
+            (Some(image), _) if self.highlighted() => image,
+            (_, Some(frame)) => &self.frames[frame],
+            _ => match self.level {
+                Some(level) => &self.levels[level].1,
+                None => &self.image
+            }
+        }
+    }
+    // Advances press highlights and animations, returns the time until the next change
+    fn update_timers(&mut self, config: &Config) -> i32 {
+        let mut next_timeout_ms = min(self.update_flash(config), self.update_animation(config));
+        next_timeout_ms = min(next_timeout_ms, self.update_level());
+        if let Some(started) = self.dwell_started {
+            // redraw the growing highlight
+            self.changed = true;
+            let remaining = self.dwell.saturating_sub(started.elapsed()).as_millis() as i32;
+            let interval = max(DWELL_REDRAW_INTERVAL_MS, config.power.min_frame_interval.as_millis() as i32);
+            next_timeout_ms = min(next_timeout_ms, min(remaining, interval));
+        }
+        if let Some(secondary) = &mut self.secondary {
+            next_timeout_ms = min(next_timeout_ms, secondary.update_timers(config));
+        }
+        next_timeout_ms
+    }
+    fn update_animation(&mut self, config: &Config) -> i32 {
+        if self.animation == Animation::WhileActive && !self.frames.is_empty() {
+            let running = self.active || self.latched;
+            if running != self.animation_started.is_some() {
+                self.animation_started = running.then(Instant::now);
+                self.frame = None;
+                self.changed = true;
+            }
+        }
+        let Some(started) = self.animation_started else {
+            return i32::MAX;
+        };
+        let frame_ms = self.frame_duration.as_millis().max(1);
+        let elapsed = started.elapsed().as_millis();
+        let mut frame = (elapsed / frame_ms) as usize;
+        if self.animation == Animation::OnPress && frame >= self.frames.len() {
+            self.animation_started = None;
+            self.frame = None;
+            self.changed = true;
+            return i32::MAX;
+        }
+        frame %= self.frames.len();
+        if self.frame != Some(frame) {
+            self.frame = Some(frame);
+            self.changed = true;
+        }
+        // the frame is picked from the elapsed time, so waking up less often skips frames
+        // instead of slowing the animation down
+        (frame_ms - elapsed % frame_ms).max(config.power.min_frame_interval.as_millis()).min(i32::MAX as u128) as i32
+    }
+    // Picks the level icon for the current value of the level source,
+    // returns the time until it should be read again
+    fn update_level(&mut self) -> i32 {
+        let Some(source) = self.level_source else {
+            return i32::MAX;
+        };
+        let elapsed = self.level_checked_at.map_or(i32::MAX as u128, |at| at.elapsed().as_millis());
+        if elapsed < LEVEL_POLL_INTERVAL_MS as u128 {
+            return LEVEL_POLL_INTERVAL_MS - elapsed as i32;
+        }
+        self.level_checked_at = Some(Instant::now());
+        let level = match read_level(source) {
+            Ok(value) => Some(self.levels.iter().position(|(max, _)| value <= *max).unwrap_or(self.levels.len() - 1)),
+            Err(e) => {
+                // only log when the source stops working, not on every poll
+                if !self.level_failed {
+                    eprintln!("Failed to read the level of the {} button: {}", self.action.name(), e);
+                }
+                None
+            }
+        };
+        let failed = level.is_none();
+        if level != self.level || failed != self.level_failed {
+            self.level = level;
+            self.level_failed = failed;
+            self.changed = true;
+        }
+        LEVEL_POLL_INTERVAL_MS
+    }
+    // Ends the highlight after a release once PressFlashMs has passed,
+    // returns the time until that happens
+    fn update_flash(&mut self, config: &Config) -> i32 {
+        let Some(released_at) = self.released_at else {
+            return i32::MAX;
+        };
+        let remaining = config.press_flash.saturating_sub(released_at.elapsed());
+        if remaining.is_zero() {
+            self.released_at = None;
+            self.changed = true;
+            return i32::MAX;
+        }
+        remaining.as_millis().min(i32::MAX as u128) as i32
+    }
+    fn icon_offset(&self, config: &Config) -> f64 {
+        self.icon_offset.unwrap_or(config.icon_offset)
+    }
+    fn render(&self, c: &Context, config: &Config, height: i32, button_left_edge: f64, button_width: u64, y_shift: f64) {
+        match self.current_image() {
             ButtonImage::Text(text) => {
+                c.save().unwrap();
+                c.set_font_face(config.font_for(text));
                 let extents = c.text_extents(text).unwrap();
                 c.move_to(
                     button_left_edge + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
                     y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round()
                 );
                 c.show_text(text).unwrap();
+                c.restore().unwrap();
+            },
+            ButtonImage::Glyph(glyph) => {
+                c.save().unwrap();
+                c.set_font_face(&config.glyph_font_face);
+                let extents = c.text_extents(glyph).unwrap();
+                c.move_to(
+                    button_left_edge + (button_width as f64 / 2.0 - extents.width() / 2.0 - extents.x_bearing()).round(),
+                    y_shift + (height as f64 / 2.0 - extents.height() / 2.0 - extents.y_bearing()).round()
+                );
+                c.show_text(glyph).unwrap();
+                c.restore().unwrap();
             },
             ButtonImage::Svg(svg) => {
                 let renderer = CairoRenderer::new(&svg);
-                let x = button_left_edge + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
-                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+                let x = self.icon_left_edge(c, config, button_left_edge, button_width);
+                let y = y_shift + ((height as f64 - config.icon_size) / 2.0 + self.icon_offset(config)).round();
 
                 renderer.render_document(c,
-                    &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64)
+                    &Rectangle::new(x, y, config.icon_size, config.icon_size)
                 ).unwrap();
+                self.render_caption(c, config, height, x, y_shift);
             }
             ButtonImage::Bitmap(surf) => {
-                let x = button_left_edge + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
-                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
-                c.set_source_surface(surf, x, y).unwrap();
-                c.rectangle(x, y, ICON_SIZE as f64, ICON_SIZE as f64);
+                let x = self.icon_left_edge(c, config, button_left_edge, button_width);
+                let y = y_shift + ((height as f64 - config.icon_size) / 2.0 + self.icon_offset(config)).round();
+                c.save().unwrap();
+                c.translate(x, y);
+                c.scale(config.icon_size / surf.width() as f64, config.icon_size / surf.height() as f64);
+                c.set_source_surface(surf, 0.0, 0.0).unwrap();
+                c.rectangle(0.0, 0.0, surf.width() as f64, surf.height() as f64);
                 c.fill().unwrap();
+                c.restore().unwrap();
+                self.render_caption(c, config, height, x, y_shift);
             }
         }
     }
-    fn set_active<F>(&mut self, uinput: &mut UInputHandle<F>, active: bool) where F: AsRawFd {
-        if self.active != active {
-            self.active = active;
-            self.changed = true;
+    fn caption_width(&self, c: &Context) -> f64 {
+        match &self.caption {
+            Some(caption) => CAPTION_SPACING_PX + c.text_extents(caption).unwrap().width(),
+            None => 0.0
+        }
+    }
+    // Icons are centered together with their caption
+    fn icon_left_edge(&self, c: &Context, config: &Config, button_left_edge: f64, button_width: u64) -> f64 {
+        button_left_edge + (button_width as f64 / 2.0 - (config.icon_size + self.caption_width(c)) / 2.0).round()
+    }
+    fn render_caption(&self, c: &Context, config: &Config, height: i32, icon_left_edge: f64, y_shift: f64) {
+        let Some(caption) = &self.caption else {
+            return;
+        };
+        let extents = c.text_extents(caption).unwrap();
+        c.move_to(
+            icon_left_edge + config.icon_size + CAPTION_SPACING_PX,
+            y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round()
+        );
+        c.show_text(caption).unwrap();
+    }
+    // Adds or removes one touch holding the button, returns whether the button fired.
+    // Callers must only remove touches they added, the button is active while any remain
+    fn set_active<F>(&mut self, uinput: &mut UInputHandle<F>, active: bool) -> bool where F: AsRawFd {
+        if active && self.presses > 0 {
+            self.presses += 1;
+            return false;
+        }
+        if !active && self.presses > 1 {
+            self.presses -= 1;
+            return false;
+        }
+        if self.active == active {
+            return false;
+        }
+        if active && self.last_fired.map_or(false, |t| t.elapsed() < self.cooldown) {
+            return false;
+        }
+        self.active = active;
+        self.presses = active as usize;
+        self.changed = true;
+        if active && !self.dwell.is_zero() {
+            // fired by update_dwell once the touch stayed long enough
+            self.dwell_started = Some(Instant::now());
+            return false;
+        }
+        if !active && self.dwell_started.take().is_some() {
+            // lifted before the dwell time passed, nothing was sent
+            return false;
+        }
+        self.fire(uinput, active)
+    }
+    fn fire<F>(&mut self, uinput: &mut UInputHandle<F>, active: bool) -> bool where F: AsRawFd {
+        if active {
+            self.last_fired = Some(Instant::now());
+        }
+        if active && self.animation == Animation::OnPress && !self.frames.is_empty() {
+            self.animation_started = Some(Instant::now());
+        }
+        self.released_at = match self.action {
+            ButtonAction::Layer(_) | ButtonAction::Blank | ButtonAction::Pointer => None,
+            _ if active => None,
+            _ => Some(Instant::now())
+        };
 
-            toggle_key(uinput, self.action, active as i32);
+        match (self.latching, self.emit_mode) {
+            // latching buttons only change state when touched
+            (true, _) => if active {
+                self.latched = !self.latched;
+                self.emit(uinput, self.latched);
+            },
+            (false, EmitMode::Tap) => if active {
+                self.emit(uinput, true);
+                self.emit(uinput, false);
+            },
+            (false, EmitMode::Repeat) => if active {
+                self.emit(uinput, true);
+                self.emit(uinput, false);
+                self.repeat_since = Some(Instant::now());
+            } else {
+                self.repeat_since = None;
+                self.repeat_at = None;
+            },
+            (false, EmitMode::Hold) => self.emit(uinput, active)
+        }
+        active
+    }
+    // Fires the button once it was held for DwellMs, returns whether it fired
+    fn update_dwell<F>(&mut self, uinput: &mut UInputHandle<F>) -> bool where F: AsRawFd {
+        match self.dwell_started {
+            Some(started) if started.elapsed() >= self.dwell => {
+                self.dwell_started = None;
+                self.changed = true;
+                self.fire(uinput, true)
+            },
+            _ => false
+        }
+    }
+    // Taps held Repeat buttons again once their repeat interval passed,
+    // returns the time until the next repeat
+    fn update_repeat<F>(&mut self, uinput: &mut UInputHandle<F>, config: &Config) -> i32 where F: AsRawFd {
+        let mut next_timeout_ms = i32::MAX;
+        if let Some(since) = self.repeat_since {
+            let now = Instant::now();
+            let repeat_at = *self.repeat_at.get_or_insert_with(|| since + config.repeat_interval(Duration::ZERO));
+            if now >= repeat_at {
+                self.emit(uinput, true);
+                self.emit(uinput, false);
+                self.repeat_at = Some(now + config.repeat_interval(now - since));
+            }
+            next_timeout_ms = self.repeat_at.unwrap().saturating_duration_since(now).as_millis() as i32;
+        }
+        if let Some(secondary) = &mut self.secondary {
+            next_timeout_ms = min(next_timeout_ms, secondary.update_repeat(uinput, config));
+        }
+        next_timeout_ms
+    }
+    // How far along the dwell of a held button is, from 0 to 1
+    fn dwell_progress(&self) -> Option<f64> {
+        self.dwell_started.map(|started| (started.elapsed().as_secs_f64() / self.dwell.as_secs_f64()).min(1.0))
+    }
+    fn emit<F>(&self, uinput: &mut UInputHandle<F>, pressed: bool) where F: AsRawFd {
+        match &self.action {
+            ButtonAction::Key(key) => match self.modifier {
+                // the modifier is held around the key
+                Some(modifier) if pressed => {
+                    toggle_key(uinput, modifier, 1);
+                    toggle_key(uinput, *key, 1);
+                },
+                Some(modifier) => {
+                    toggle_key(uinput, *key, 0);
+                    toggle_key(uinput, modifier, 0);
+                },
+                None => toggle_key(uinput, *key, pressed as i32)
+            },
+            ButtonAction::Glyph(glyph) => if pressed {
+                type_glyph(uinput, glyph)
+            },
+            // handled by the main loop, as it owns the active layer, backlight and pointer device
+            ButtonAction::Layer(_) | ButtonAction::Blank | ButtonAction::Pointer => {}
+        }
+    }
+    // Latches the button again after a restart, pressing its key like the touch that latched it did
+    fn restore_latch<F>(&mut self, uinput: &mut UInputHandle<F>) where F: AsRawFd {
+        if self.latching && self.persist_latch && !self.latched {
+            self.latched = true;
+            self.changed = true;
+            self.emit(uinput, true);
+        }
+    }
+    fn unlatch<F>(&mut self, uinput: &mut UInputHandle<F>) where F: AsRawFd {
+        if self.latched {
+            self.latched = false;
+            self.changed = true;
+            self.emit(uinput, false);
+        }
+    }
+    // Lets go of the button no matter how many touches hold it, including a latched one
+    fn release<F>(&mut self, uinput: &mut UInputHandle<F>) where F: AsRawFd {
+        self.presses = self.presses.min(1);
+        self.set_active(uinput, false);
+        self.unlatch(uinput);
+        if let Some(secondary) = &mut self.secondary {
+            secondary.release(uinput);
+        }
+    }
+    // Whether the button sends the same events as other, so that keys held or latched
+    // through other can be released through this button instead
+    fn same_input(&self, other: &Button) -> bool {
+        let same_secondary = match (&self.secondary, &other.secondary) {
+            (Some(a), Some(b)) => a.same_input(b),
+            (None, None) => true,
+            _ => false
+        };
+        same_secondary && self.action.name() == other.action.name() && self.keys() == other.keys()
+            && self.modifier == other.modifier && self.latching == other.latching && self.emit_mode == other.emit_mode
+    }
+    // Carries over what is held and latched from the button this one replaces after a reload
+    fn take_state_from(&mut self, old: &Button) {
+        self.active = old.active;
+        self.presses = old.presses;
+        self.latched = old.latched;
+        self.last_fired = old.last_fired;
+        self.released_at = old.released_at;
+        self.dwell_started = old.dwell_started;
+        self.repeat_since = old.repeat_since;
+        self.repeat_at = old.repeat_at;
+        if let (Some(new), Some(old)) = (&mut self.secondary, &old.secondary) {
+            new.take_state_from(old);
+        }
+    }
+    // Highlights the parts of the button that send key like a release does, without firing them
+    fn flash_key(&mut self, key: u32) {
+        if !self.active && matches!(self.action, ButtonAction::Key(k) if k as u32 == key) {
+            self.released_at = Some(Instant::now());
+            self.changed = true;
         }
+        if let Some(secondary) = &mut self.secondary {
+            secondary.flash_key(key);
+        }
+    }
+    // Whether firing this button ends latched sticky buttons
+    fn consumes_sticky(&self) -> bool {
+        !self.sticky && matches!(self.action, ButtonAction::Key(_) | ButtonAction::Glyph(_))
     }
 }
 
-#[derive(Default)]
 struct FunctionLayer {
-    buttons: Vec<Button>
+    buttons: Vec<Button>,
+    background: Background,
+    // backlight level used instead of the default one while the layer is active
+    brightness: Option<u32>,
+    // shown as an overlay when switching to the layer
+    name: Option<String>,
+    // width of the widest button content, used by the compact layout
+    content_width: f64,
+    // the glyph set a page at a time, the page that is shown is empty here
+    // as its buttons are the last glyph_count ones of buttons
+    glyph_pages: Vec<Vec<Button>>,
+    glyph_page: usize,
+    glyph_count: usize
 }
 
 impl FunctionLayer {
-    fn with_config(cfg: Vec<ButtonConfig>) -> FunctionLayer {
-        if cfg.is_empty() {
-            panic!("Invalid configuration, layer has 0 buttons");
-        }
+    fn with_config(cfg: Vec<ButtonConfig>, config: &Config, background: Background, brightness: Option<u32>, name: Option<String>) -> FunctionLayer {
         FunctionLayer {
-            buttons: cfg.into_iter().map(Button::with_config).collect()
+            buttons: cfg.into_iter().map(|button| Button::with_config(button, config)).collect(),
+            background,
+            brightness,
+            name,
+            content_width: 0.0,
+            glyph_pages: Vec::new(),
+            glyph_page: 0,
+            glyph_count: 0
+        }
+    }
+    // Appends glyphs as Glyph buttons, per_page at a time, with a split button
+    // in front of them to turn the pages if there is more than one
+    fn add_glyph_set(&mut self, glyphs: Vec<String>, per_page: usize, config: &Config) {
+        let glyph_button = |glyph: &String| Button::with_config(ButtonConfig { glyph: Some(glyph.clone()), ..Default::default() }, config);
+        let mut pages: Vec<Vec<Button>> = glyphs.chunks(per_page.max(1)).map(|page| page.iter().map(glyph_button).collect()).collect();
+        if pages.is_empty() {
+            return;
+        }
+        if pages.len() > 1 {
+            let turn = |text: &str, action| ButtonConfig {
+                text: Some(text.to_string()),
+                action: Some(ActionConfig::Layer(action)),
+                ..Default::default()
+            };
+            let mut turn_pages = turn("◀", LayerAction::PreviousPage);
+            turn_pages.secondary = Some(Box::new(turn("▶", LayerAction::NextPage)));
+            self.buttons.push(Button::with_config(turn_pages, config));
+        }
+        let first = std::mem::take(&mut pages[0]);
+        self.glyph_count = first.len();
+        self.buttons.extend(first);
+        self.glyph_pages = pages;
+        self.glyph_page = 0;
+    }
+    // Index of the first glyph button that is shown
+    fn first_glyph(&self) -> usize {
+        self.buttons.len() - self.glyph_count
+    }
+    // Shows the next or previous page of the glyph set, wrapping around
+    fn turn_page<F>(&mut self, forward: bool, uinput: &mut UInputHandle<F>) where F: AsRawFd {
+        let count = self.glyph_pages.len();
+        if count < 2 {
+            return;
         }
+        let first_glyph = self.first_glyph();
+        let mut shown: Vec<Button> = self.buttons.drain(first_glyph..).collect();
+        for button in &mut shown {
+            button.release(uinput);
+        }
+        self.glyph_pages[self.glyph_page] = shown;
+        self.glyph_page = if forward { (self.glyph_page + 1) % count } else { (self.glyph_page + count - 1) % count };
+        let page = std::mem::take(&mut self.glyph_pages[self.glyph_page]);
+        self.glyph_count = page.len();
+        self.buttons.extend(page);
     }
-    fn draw(&mut self, config: &Config, width: i32, height: i32, surface: &Surface, pixel_shift: (f64, f64), complete_redraw: bool) -> Vec<ClipRect> {
+    fn measure(&mut self, config: &Config) {
+        let surface = ImageSurface::create(Format::ARgb32, 1, 1).unwrap();
         let c = Context::new(&surface).unwrap();
+        c.set_font_face(&config.font.face);
+        c.set_font_size(FONT_SIZE);
+        // every page, so that the buttons keep their width when turning pages
+        self.content_width = self.buttons.iter().chain(self.glyph_pages.iter().flatten())
+            .map(|b| b.content_width(&c, config))
+            .fold(0.0, f64::max);
+    }
+    fn geometry(&self, config: &Config, width: i32) -> LayerGeometry {
+        let (width, origin) = layout_span(config, width);
+        let stretched = LayerGeometry::new(self.buttons.len(), width, origin, BUTTON_SPACING_PX);
+        if config.layout == Layout::Stretch {
+            return stretched;
+        }
+        let compact = LayerGeometry::new(self.buttons.len(), width, origin, COMPACT_BUTTON_SPACING_PX);
+        let button_width = self.content_width + COMPACT_BUTTON_PADDING_PX * 2.0;
+        // fall back to stretching if the contents do not fit with the wider spacing
+        if button_width > compact.button_width {
+            return stretched;
+        }
+        compact.shrink_to(button_width, width)
+    }
+    // hints are drawn as small captions on top of the buttons with the same index
+    fn draw(&mut self, config: &Config, width: i32, height: i32, surface: &Surface, pixel_shift: (f64, f64), complete_redraw: bool, hints: Option<&[String]>) -> Vec<ClipRect> {
+        let c = panel_context(surface, config, width, height);
         let mut modified_regions = if complete_redraw {
             vec![ClipRect::new(0, 0, height as u16, width as u16)]
         } else {
             Vec::new()
         };
-        c.translate(height as f64, 0.0);
-        c.rotate((90.0f64).to_radians());
-        let pixel_shift_width = if config.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX } else { 0 };
-        let button_width = ((width - pixel_shift_width as i32) - (BUTTON_SPACING_PX * (self.buttons.len() - 1) as i32)) as f64 / self.buttons.len() as f64;
+        let geometry = self.geometry(config, width);
+        let button_width = geometry.button_width;
         let radius = 8.0f64;
         let bot = (height as f64) * 0.15;
         let top = (height as f64) * 0.85;
         let (pixel_shift_x, pixel_shift_y) = pixel_shift;
 
         if complete_redraw {
-            c.set_source_rgb(0.0, 0.0, 0.0);
+            self.background.set_source(&c, config);
             c.paint().unwrap();
         }
-        c.set_font_face(&config.font_face);
-        c.set_font_size(32.0);
+        c.set_font_face(&config.font.face);
+        c.set_font_size(FONT_SIZE);
         for (i, button) in self.buttons.iter_mut().enumerate() {
-            if !button.changed && !complete_redraw {
+            if !button.needs_redraw() && !complete_redraw {
                 continue;
             };
 
-            let left_edge = (i as f64 * (button_width + BUTTON_SPACING_PX as f64)).floor() + pixel_shift_x + (pixel_shift_width / 2) as f64;
-            let color = if button.active {
-                BUTTON_COLOR_ACTIVE
-            } else if config.show_button_outlines {
-                BUTTON_COLOR_INACTIVE
-            } else {
-                0.0
-            };
+            let left_edge = geometry.left_edge(i) + pixel_shift_x;
+            // the outline reaches up to the rounded up width
+            let (clear_left, clear_right) = column_span(width, left_edge, left_edge + button_width.ceil());
             if !complete_redraw {
-                c.set_source_rgb(0.0, 0.0, 0.0);
-                c.rectangle(left_edge, bot - radius, button_width, top - bot + radius * 2.0);
+                self.background.set_source(&c, config);
+                c.rectangle(clear_left, 0.0, clear_right - clear_left, height as f64);
                 c.fill().unwrap();
             }
-            c.set_source_rgb(color, color, color);
             // draw box with rounded corners
             c.new_sub_path();
             let left = left_edge + radius;
@@ -268,20 +1219,80 @@ impl FunctionLayer {
                 (270.0f64).to_radians(),
             );
             c.close_path();
+            let outline = c.copy_path().unwrap();
+            c.new_path();
 
-            c.fill().unwrap();
-            c.set_source_rgb(1.0, 1.0, 1.0);
-            button.render(&c, height, left_edge, button_width.ceil() as u64, pixel_shift_y);
+            // split buttons are drawn as two halves, each highlighted on its own
+            let half_width = button_width / 2.0;
+            let parts = match &button.secondary {
+                Some(secondary) => vec![(&*button, left_edge, half_width), (&**secondary, left_edge + half_width, half_width)],
+                None => vec![(&*button, left_edge, button_width)]
+            };
+            for (part, part_left_edge, part_width) in parts {
+                let color = if part.highlighted() {
+                    Some(part.highlight_color())
+                } else if config.show_button_outlines || part.color.is_some() {
+                    Some(part.color.unwrap_or(Color::gray(BUTTON_COLOR_INACTIVE)))
+                } else {
+                    None
+                };
+                // otherwise leave the background visible
+                if let Some(color) = color {
+                    c.save().unwrap();
+                    c.rectangle(part_left_edge, 0.0, part_width, height as f64);
+                    c.clip();
+                    c.append_path(&outline);
+                    config.set_source_color(&c, color);
+                    c.fill().unwrap();
+                    c.restore().unwrap();
+                }
+                if let Some(progress) = part.dwell_progress() {
+                    c.save().unwrap();
+                    c.rectangle(part_left_edge, 0.0, part_width * progress, height as f64);
+                    c.clip();
+                    c.append_path(&outline);
+                    config.set_source_color(&c, part.highlight_color());
+                    c.fill().unwrap();
+                    c.restore().unwrap();
+                }
+                match part.text_color {
+                    Some(color) => config.set_source_color(&c, color),
+                    None => c.set_source_rgb(1.0, 1.0, 1.0)
+                }
+                if part.unavailable() {
+                    c.push_group();
+                }
+                part.render(&c, config, height, part_left_edge, part_width.ceil() as u64, pixel_shift_y);
+                if part.unavailable() {
+                    c.pop_group_to_source().unwrap();
+                    c.paint_with_alpha(UNAVAILABLE_ALPHA).unwrap();
+                }
+            }
+            if let Some(hint) = hints.and_then(|hints| hints.get(i)) {
+                c.save().unwrap();
+                c.set_font_size(HINT_FONT_SIZE);
+                let extents = c.text_extents(hint).unwrap();
+                c.move_to(
+                    left_edge + (button_width / 2.0 - extents.width() / 2.0).round(),
+                    (bot + HINT_FONT_SIZE).round() + pixel_shift_y
+                );
+                c.show_text(hint).unwrap();
+                c.restore().unwrap();
+            }
+            if button.secondary.is_some() {
+                config.set_source_color(&c, Color::gray(BUTTON_COLOR_DIVIDER));
+                c.rectangle((left_edge + half_width).round() - 1.0, bot, 2.0, top - bot);
+                c.fill().unwrap();
+            }
 
             button.changed = false;
+            if let Some(secondary) = &mut button.secondary {
+                secondary.changed = false;
+            }
 
             if !complete_redraw {
-                modified_regions.push(ClipRect::new(
-                    height as u16 - top as u16 - radius as u16,
-                    left_edge as u16,
-                    height as u16 - bot as u16 + radius as u16,
-                    left_edge as u16 + button_width as u16
-                ));
+                // cover the whole column, icons may be nudged outside of the button outline
+                modified_regions.push(column_clip(config.panel_orientation, width, height, clear_left, clear_right));
             }
         }
 
@@ -309,15 +1320,143 @@ impl LibinputInterface for Interface {
 }
 
 
-fn button_hit(num: u32, idx: u32, width: u16, height: u16, x: f64, y: f64) -> bool {
-    let button_width = (width as i32 - (BUTTON_SPACING_PX * (num - 1) as i32)) as f64 / num as f64;
-    let left_edge = idx as f64 * (button_width + BUTTON_SPACING_PX as f64);
-    if x < left_edge || x > (left_edge + button_width) {
-        return false
+// Returns a context where x runs along the bar and y across it
+fn panel_context(surface: &Surface, config: &Config, width: i32, height: i32) -> Context {
+    let c = Context::new(surface).unwrap();
+    c.set_antialias(config.antialias);
+    match config.panel_orientation {
+        PanelOrientation::RightSideUp => {
+            c.translate(height as f64, 0.0);
+            c.rotate((90.0f64).to_radians());
+        },
+        PanelOrientation::LeftSideUp => {
+            c.translate(0.0, width as f64);
+            c.rotate((-90.0f64).to_radians());
+        }
+    }
+    c
+}
+
+// Whole pixels covering left to right and one more on each side, so that clearing and
+// flushing a button also replaces the antialiased pixels along its previous edges
+fn column_span(width: i32, left: f64, right: f64) -> (f64, f64) {
+    ((left.floor() - 1.0).max(0.0), (right.ceil() + 1.0).min(width as f64))
+}
+
+// The local time as shown by IdleMode = "Clock" and the time until the next minute
+fn clock_text() -> (String, i32) {
+    let now = clock_gettime(ClockId::CLOCK_REALTIME).unwrap();
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now.tv_sec(), &mut tm) }.is_null() {
+        // shown instead of a wrong time, tried again in a minute
+        return ("--:--".to_string(), 60 * 1000);
+    }
+    let next_minute_ms = (60 - tm.tm_sec as i64) * 1000 - now.tv_nsec() as i64 / 1_000_000;
+    (format!("{:02}:{:02}", tm.tm_hour, tm.tm_min), next_minute_ms.max(1) as i32)
+}
+
+// Framebuffer region covering the full height of the bar between left and right,
+// which are rounded outwards
+fn column_clip(panel_orientation: PanelOrientation, width: i32, height: i32, left: f64, right: f64) -> ClipRect {
+    let (left, right) = (left.floor().max(0.0) as u16, right.ceil().min(width as f64) as u16);
+    match panel_orientation {
+        PanelOrientation::RightSideUp => ClipRect::new(0, left, height as u16, right),
+        PanelOrientation::LeftSideUp => ClipRect::new(0, width as u16 - right, height as u16, width as u16 - left)
+    }
+}
+
+fn draw_overlay(config: &Config, background: &Background, text: &str, width: i32, height: i32, surface: &Surface) -> Vec<ClipRect> {
+    let c = panel_context(surface, config, width, height);
+    background.set_source(&c, config);
+    c.paint().unwrap();
+    c.set_font_face(&config.font.face);
+    c.set_font_size(FONT_SIZE);
+    c.set_source_rgb(1.0, 1.0, 1.0);
+    let extents = c.text_extents(text).unwrap();
+    c.move_to(
+        ((width as f64 - extents.width()) / 2.0).round(),
+        ((height as f64 + extents.height()) / 2.0).round()
+    );
+    c.show_text(text).unwrap();
+    vec![ClipRect::new(0, 0, height as u16, width as u16)]
+}
+
+// Placement of the buttons of a layer along the bar, shared by drawing
+// and touch handling so that both always agree on where a button is.
+// The width the buttons are laid out in and where it starts,
+// leaving room for pixel shift and the insets
+fn layout_span(config: &Config, width: i32) -> (i32, f64) {
+    let pixel_shift_width = if config.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX } else { 0 };
+    let (left_inset, right_inset) = (inset_px(config.left_inset, width), inset_px(config.right_inset, width));
+    let origin = (pixel_shift_width / 2 + left_inset as u64) as f64;
+    (width - pixel_shift_width as i32 - left_inset - right_inset, origin)
+}
+
+// Insets below 1 are a fraction of the bar width, larger ones are in pixels
+fn inset_px(inset: f64, width: i32) -> i32 {
+    if inset < 1.0 {
+        (inset.max(0.0) * width as f64).round() as i32
+    } else {
+        inset.round() as i32
+    }
+}
+
+struct LayerGeometry {
+    count: usize,
+    origin: f64,
+    button_width: f64,
+    spacing: f64
+}
+
+impl LayerGeometry {
+    fn new(count: usize, width: i32, origin: f64, spacing: i32) -> LayerGeometry {
+        let button_width = (width - spacing * (count as i32 - 1)) as f64 / count as f64;
+        LayerGeometry { count, origin, button_width, spacing: spacing as f64 }
+    }
+    // Narrows the buttons to button_width, keeping the row centered within width
+    fn shrink_to(self, button_width: f64, width: i32) -> LayerGeometry {
+        let button_width = button_width.ceil();
+        let row_width = button_width * self.count as f64 + self.spacing * (self.count - 1) as f64;
+        LayerGeometry {
+            origin: self.origin + ((width as f64 - row_width) / 2.0).floor(),
+            button_width,
+            ..self
+        }
+    }
+    fn left_edge(&self, idx: usize) -> f64 {
+        self.origin + (idx as f64 * (self.button_width + self.spacing)).floor()
+    }
+    // Index of the button whose slot (the button and the spacing after it) contains x
+    fn button_at(&self, x: f64) -> Option<usize> {
+        let idx = ((x - self.origin) / (self.button_width + self.spacing)).floor();
+        if idx < -1.0 || idx >= self.count as f64 {
+            return None
+        }
+        // left_edge rounds down, so the next button can start up to a pixel
+        // before its slot does
+        let next = (idx + 1.0) as usize;
+        if next < self.count && x >= self.left_edge(next) {
+            return Some(next)
+        }
+        if idx < 0.0 {
+            return None
+        }
+        Some(idx as usize)
+    }
+    fn hit(&self, idx: usize, height: u16, x: f64, y: f64) -> bool {
+        let left_edge = self.left_edge(idx);
+        if x < left_edge || x > (left_edge + self.button_width) {
+            return false
+        }
+        y > 0.1 * height as f64 && y < 0.9 * height as f64
+    }
+    fn in_right_half(&self, idx: usize, x: f64) -> bool {
+        x > self.left_edge(idx) + self.button_width / 2.0
     }
-    y > 0.1 * height as f64 && y < 0.9 * height as f64
 }
 
+// uinput ignores the time of written events, the kernel stamps them when they are
+// delivered to readers of the virtual device, so it is left at zero
 fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32) where F: AsRawFd {
     uinput.write(&[input_event {
         value: value,
@@ -330,12 +1469,56 @@ fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32) w
     }]).unwrap();
 }
 
+// Moves the cursor to where x, y is within the button, the button spans the whole screen
+fn move_pointer<F>(pointer: &mut UInputHandle<F>, geometry: &LayerGeometry, btn: usize, height: u16, x: f64, y: f64) where F: AsRawFd {
+    let x = ((x - geometry.left_edge(btn)) / geometry.button_width).clamp(0.0, 1.0);
+    let y = (y / height as f64).clamp(0.0, 1.0);
+    emit(pointer, EventKind::Absolute, AbsoluteAxis::X as u16, (x * POINTER_ABS_MAX as f64).round() as i32);
+    emit(pointer, EventKind::Absolute, AbsoluteAxis::Y as u16, (y * POINTER_ABS_MAX as f64).round() as i32);
+    emit(pointer, EventKind::Synchronize, SynchronizeKind::Report as u16, 0);
+}
+
 fn toggle_key<F>(uinput: &mut UInputHandle<F>, code: Key, value: i32) where F: AsRawFd {
     emit(uinput, EventKind::Key, code as u16, value);
     emit(uinput, EventKind::Synchronize, SynchronizeKind::Report as u16, 0);
 }
 
-fn load_font(name: &str) -> FontFace {
+fn tap_key<F>(uinput: &mut UInputHandle<F>, code: Key) where F: AsRawFd {
+    toggle_key(uinput, code, 1);
+    toggle_key(uinput, code, 0);
+}
+
+fn type_glyph<F>(uinput: &mut UInputHandle<F>, glyph: &str) where F: AsRawFd {
+    for ch in glyph.chars() {
+        toggle_key(uinput, Key::LeftCtrl, 1);
+        toggle_key(uinput, Key::LeftShift, 1);
+        tap_key(uinput, Key::U);
+        toggle_key(uinput, Key::LeftShift, 0);
+        toggle_key(uinput, Key::LeftCtrl, 0);
+        for digit in format!("{:x}", ch as u32).chars() {
+            tap_key(uinput, GLYPH_KEYS[digit.to_digit(16).unwrap() as usize]);
+        }
+        tap_key(uinput, Key::Space);
+    }
+}
+
+struct Font {
+    face: FontFace,
+    // kept around to look up which characters the font has,
+    // fonts without a file, like cairo's builtin one, are taken to only have ASCII
+    ft_face: Option<FtFace>
+}
+
+impl Font {
+    fn has_chars(&self, text: &str) -> bool {
+        match &self.ft_face {
+            Some(ft_face) => text.chars().all(|ch| ft_face.get_char_index(ch as usize) != 0),
+            None => text.is_ascii()
+        }
+    }
+}
+
+fn load_font(name: &str) -> Font {
     let fontconfig = FontConfig::new();
     let mut pattern = Pattern::new(name);
     fontconfig.perform_substitutions(&mut pattern);
@@ -343,46 +1526,251 @@ fn load_font(name: &str) -> FontFace {
     let file_name = pat_match.get_file_name();
     let file_idx = pat_match.get_font_index();
     let ft_library = FtLibrary::init().unwrap();
-    let face = ft_library.new_face(file_name, file_idx).unwrap();
-    FontFace::create_from_ft(&face).unwrap()
-}
-
-fn load_config(width: u16) -> (Config, [FunctionLayer; 2]) {
-    let mut base = toml::from_str::<ConfigProxy>(&read_to_string("/usr/share/tiny-dfr/config.toml").unwrap()).unwrap();
-    let user = read_to_string(USER_CFG_PATH).map_err::<Error, _>(|e| e.into())
-        .and_then(|r| Ok(toml::from_str::<ConfigProxy>(&r)?));
-    if let Ok(user) = user {
-        base.media_layer_default = user.media_layer_default.or(base.media_layer_default);
-        base.show_button_outlines = user.show_button_outlines.or(base.show_button_outlines);
-        base.enable_pixel_shift = user.enable_pixel_shift.or(base.enable_pixel_shift);
-        base.font_template = user.font_template.or(base.font_template);
-        base.media_layer_keys = user.media_layer_keys.or(base.media_layer_keys);
-        base.primary_layer_keys = user.primary_layer_keys.or(base.primary_layer_keys);
-    };
-    let media_layer = FunctionLayer::with_config(base.media_layer_keys.unwrap());
-    let fkey_layer = FunctionLayer::with_config(base.primary_layer_keys.unwrap());
-    let mut layers = if base.media_layer_default.unwrap(){ [media_layer, fkey_layer] } else { [fkey_layer, media_layer] };
-
-    if width >= 2170 {
-        for layer in &mut layers {
-            layer.buttons.insert(0, Button::new_text("esc".to_string(), Key::Esc));
-        }
+    let ft_face = ft_library.new_face(file_name, file_idx).unwrap();
+    Font {
+        face: FontFace::create_from_ft(&ft_face).unwrap(),
+        ft_face: Some(ft_face)
     }
+}
 
-    let cfg = Config {
-        show_button_outlines: base.show_button_outlines.unwrap(),
+// Rereads the config files on a reload, failing under OnConfigError = Fail ends on the crash screen
+fn reload_config(width: u16, height: u16, panel_orientation: Option<PanelOrientation>) -> (Config, [FunctionLayer; 2]) {
+    let base = read_config().unwrap_or_else(|e| panic!("{}", e));
+    build_config(base, width, height, panel_orientation, load_font)
+}
+
+// Reads the config files before anything is set up, failing under OnConfigError = Fail exits
+fn read_startup_config() -> ConfigProxy {
+    read_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+// Turns the merged config file into the config and layers in effect,
+// fonts come from load_font so that this also works without fontconfig
+fn build_config(
+    mut base: ConfigProxy, width: u16, height: u16, panel_orientation: Option<PanelOrientation>,
+    load_font: impl Fn(&str) -> Font
+) -> (Config, [FunctionLayer; 2]) {
+    base.apply_render_quality();
+    let (max_fps, animations, antialiasing) = (base.max_fps.unwrap(), base.animations.unwrap(), base.antialiasing.unwrap());
+    let base_power = PowerProfile {
+        dim_timeout_ms: base.dim_timeout_ms,
+        off_timeout_ms: base.off_timeout_ms,
+        max_brightness: base.max_brightness,
+        max_fps: Some(max_fps),
+        always_on: base.always_on
+    };
+    let (ac_power, battery_power) = match (base.ac_power, base.battery_power) {
+        (Some(ac), Some(battery)) => (ac, battery),
+        // a single profile applies no matter the power state
+        (Some(profile), None) | (None, Some(profile)) => (profile, profile),
+        (None, None) => (PowerProfile::default(), PowerProfile::default())
+    };
+    let ac_power = PowerSettings::from_profile(ac_power.or(base_power));
+    let battery_power = PowerSettings::from_profile(battery_power.or(base_power));
+    let cfg = Config {
+        show_button_outlines: base.show_button_outlines.unwrap(),
         enable_pixel_shift: base.enable_pixel_shift.unwrap(),
-        font_face: load_font(&base.font_template.unwrap()),
+        font: load_font(&base.font_template.unwrap()),
+        fallback_fonts: base.fallback_font_templates.unwrap().iter().map(|name| load_font(name)).collect(),
+        glyph_font_face: load_font(&base.glyph_font_template.unwrap()).face,
+        fn_mode: base.fn_mode.unwrap(),
+        fn_key: base.fn_key.unwrap(),
+        animations,
+        antialias: match antialiasing {
+            Antialiasing::Fast => Antialias::Fast,
+            Antialiasing::Good => Antialias::Good,
+            Antialiasing::Best => Antialias::Best
+        },
+        fn_tap_threshold: Duration::from_millis(base.fn_tap_threshold_ms.unwrap()),
+        icon_offset: base.icon_offset.unwrap(),
+        layout: base.layout.unwrap(),
+        left_inset: base.left_inset.unwrap(),
+        right_inset: base.right_inset.unwrap(),
+        min_button_width: base.min_button_width.unwrap(),
+        overflow: base.overflow.unwrap(),
+        color_gamma: base.color_gamma.unwrap(),
+        backlight_min_on: Duration::from_millis(base.backlight_min_on_ms.unwrap()),
+        // the orientation reported by the kernel takes precedence over the config
+        panel_orientation: panel_orientation.unwrap_or(base.panel_orientation.unwrap()),
+        next_layer_key: base.next_layer_key,
+        keyboard_profiles: base.keyboard_profiles.unwrap_or_default(),
+        mirror_keyboard: base.mirror_keyboard.unwrap(),
+        previous_layer_key: base.previous_layer_key,
+        press_flash: Duration::from_millis(base.press_flash_ms.unwrap()),
+        dwell: Duration::from_millis(base.dwell_ms.unwrap()),
+        persist_latched: base.persist_latched.unwrap(),
+        key_modifier: base.key_modifier,
+        idle_mode: base.idle_mode.unwrap(),
+        repeat_start_rate: base.repeat_start_rate.unwrap(),
+        repeat_end_rate: base.repeat_end_rate.unwrap(),
+        repeat_ramp: Duration::from_millis(base.repeat_ramp_ms.unwrap()),
+        wake_brightness: base.wake_brightness.unwrap(),
+        background_action: base.background_action.map(|action| match ButtonAction::from(action) {
+            ButtonAction::Pointer => panic!("Invalid config, BackgroundAction can not be Pointer"),
+            action => action
+        }),
+        startup_grace: Duration::from_millis(base.startup_grace_ms.unwrap()),
+        debug_draw_timing: base.debug_draw_timing.unwrap(),
+        fn_hints: Duration::from_millis(base.fn_hints_ms.unwrap()),
+        layer_name_overlay: Duration::from_millis(base.layer_name_overlay_ms.unwrap()),
+        power: ac_power,
+        ac_power,
+        battery_power,
+        drop_privileges: base.drop_privileges.unwrap(),
+        priv_drop_user: base.priv_drop_user.unwrap(),
+        priv_drop_groups: base.priv_drop_groups.unwrap(),
+        stuck_touch_timeout: Duration::from_millis(base.stuck_touch_timeout_ms.unwrap()),
+        resume_redraw: Duration::from_millis(base.resume_redraw_ms.unwrap()),
+        icon_size: ICON_SIZE as f64 * base.icon_scale.unwrap(),
+        touch_calibration: base.touch_calibration.unwrap(),
+        debug_key_overlay: base.debug_key_overlay.unwrap(),
     };
+
+    let media_layer_default = base.media_layer_default.unwrap();
+    let (mut media_layer_keys, mut primary_layer_keys) = (base.media_layer_keys.unwrap(), base.primary_layer_keys.unwrap());
+    if base.layer_preset.unwrap() == LayerPreset::Combined {
+        if media_layer_default {
+            media_layer_keys.extend(primary_layer_keys.iter().cloned());
+        } else {
+            primary_layer_keys.extend(media_layer_keys.iter().cloned());
+        }
+    }
+    let media_layer = FunctionLayer::with_config(
+        media_layer_keys,
+        &cfg,
+        Background::load(&base.media_layer_background.unwrap(), width as i32, height as i32),
+        base.media_layer_brightness,
+        base.media_layer_name
+    );
+    let fkey_layer = FunctionLayer::with_config(
+        primary_layer_keys,
+        &cfg,
+        Background::load(&base.primary_layer_background.unwrap(), width as i32, height as i32),
+        base.primary_layer_brightness,
+        base.primary_layer_name
+    );
+    let mut layers = if media_layer_default { [media_layer, fkey_layer] } else { [fkey_layer, media_layer] };
+    let glyphs_per_page = base.glyphs_per_page.unwrap() as usize;
+    let (media_glyphs, primary_glyphs) = (base.media_layer_glyphs.unwrap_or_default(), base.primary_layer_glyphs.unwrap_or_default());
+    let glyph_sets = if media_layer_default { [media_glyphs, primary_glyphs] } else { [primary_glyphs, media_glyphs] };
+
+    for (layer, glyphs) in layers.iter_mut().zip(glyph_sets) {
+        layer.add_glyph_set(glyphs, glyphs_per_page, &cfg);
+        if layer.buttons.is_empty() {
+            panic!("Invalid configuration, layer has 0 buttons");
+        }
+        if width >= 2170 {
+            layer.buttons.insert(0, Button::new_text("esc".to_string(), ButtonAction::Key(Key::Esc)));
+        }
+        // the stretched layout is the widest buttons can get
+        let (span, _) = layout_span(&cfg, width as i32);
+        let fitting = ((span + BUTTON_SPACING_PX) / (cfg.min_button_width as i32 + BUTTON_SPACING_PX)).max(1) as usize;
+        if cfg.min_button_width > 0 && layer.buttons.len() > fitting {
+            match cfg.overflow {
+                Overflow::Shrink => eprintln!(
+                    "Warning: {} buttons do not fit at MinButtonWidth, only {} do, they are drawn narrower",
+                    layer.buttons.len(), fitting
+                ),
+                Overflow::Drop => {
+                    eprintln!("Warning: only {} of {} buttons fit at MinButtonWidth, the last ones are left out", fitting, layer.buttons.len());
+                    let dropped = layer.buttons.len() - fitting;
+                    layer.glyph_count = layer.glyph_count.saturating_sub(dropped);
+                    layer.buttons.truncate(fitting);
+                }
+            }
+        }
+        layer.measure(&cfg);
+    }
     (cfg, layers)
 }
 
+fn list_actions() {
+    for name in key_names() {
+        println!("{}", name);
+    }
+}
+
+// Prints the merged config files with RenderQuality and the command line applied,
+// no_drop_privs overrides DropPrivileges like it does for the daemon
+fn dump_config(no_drop_privs: bool) {
+    let mut base = read_startup_config();
+    base.apply_render_quality();
+    if no_drop_privs {
+        base.drop_privileges = Some(false);
+    }
+    match toml::to_string_pretty(&base) {
+        Ok(dump) => print!("{}", dump),
+        Err(e) => {
+            eprintln!("Failed to write out the config: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn list_icons() {
+    let mut icons = BTreeSet::new();
+    for dir in ICON_DIRS {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_icon = path.extension().map_or(false, |ext| ext == "svg" || ext == "png");
+            if let (true, Some(stem)) = (is_icon, path.file_stem()) {
+                // name@{size} files are other sizes of the same icon
+                let stem = stem.to_string_lossy();
+                icons.insert(stem.split('@').next().unwrap().to_string());
+            }
+        }
+    }
+    for icon in icons {
+        println!("{}", icon);
+    }
+}
+
 fn main() {
-    let mut drm = DrmBackend::open_card().unwrap();
+    let mut calibrate = false;
+    let mut no_drop_privs = false;
+    let mut dump = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--list-actions" | "--list-keys" => return list_actions(),
+            "--list-icons" => return list_icons(),
+            "--list-displays" => {
+                if let Err(e) = DrmBackend::list_displays() {
+                    eprintln!("Failed to list the displays: {}", e);
+                    std::process::exit(1);
+                }
+                return
+            },
+            "--dump-config" => dump = true,
+            "--calibrate" => calibrate = true,
+            "--no-drop-privs" => no_drop_privs = true,
+            _ => {
+                eprintln!("Unknown argument: {}", arg);
+                eprintln!("Usage: tiny-dfr [--list-actions | --list-keys | --list-icons | --list-displays | --dump-config [--no-drop-privs] | [--calibrate] [--no-drop-privs]]");
+                std::process::exit(1);
+            }
+        }
+    }
+    if dump {
+        return dump_config(no_drop_privs);
+    }
+    let base = read_startup_config();
+    let grace = Duration::from_millis(base.startup_grace_ms.unwrap());
+    let connector = base.display_connector.clone();
+    let mut drm = retry_during_startup("open the touchbar display", grace, || DrmBackend::open_card(connector.as_deref()));
     let (height, width) = drm.mode().size();
-    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
-        real_main(&mut drm)
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        real_main(&mut drm, base, calibrate, no_drop_privs)
     }));
+    if result.is_ok() {
+        // real_main only returns when asked to terminate
+        return;
+    }
     let crash_bitmap = include_bytes!("crash_bitmap.raw");
     let mut map = drm.map().unwrap();
     let data = map.as_mut();
@@ -410,46 +1798,492 @@ fn arm_inotify(inotify_fd: &Inotify) -> WatchDescriptor {
     inotify_fd.add_watch(USER_CFG_PATH, flags).unwrap()
 }
 
-fn real_main(drm: &mut DrmBackend) {
-    let (height, width) = drm.mode().size();
-    let (db_width, db_height) = drm.fb_info().unwrap().size();
-    let mut uinput = UInputHandle::new(OpenOptions::new().write(true).open("/dev/uinput").unwrap());
-    let mut backlight = BacklightManager::new();
-    let (mut cfg, mut layers) = load_config(width);
-    let mut pixel_shift = PixelShiftManager::new();
+// Paints the whole bar black while it is disabled with SIGUSR1
+fn draw_blank(width: i32, height: i32, surface: &Surface) -> Vec<ClipRect> {
+    let c = Context::new(surface).unwrap();
+    c.set_source_rgb(0.0, 0.0, 0.0);
+    c.paint().unwrap();
+    vec![ClipRect::new(0, 0, height as u16, width as u16)]
+}
+
+// Called after another key was pressed, its press is already synced so the
+// sticky modifiers apply to it
+fn release_sticky<F>(layers: &mut [FunctionLayer], uinput: &mut UInputHandle<F>) where F: AsRawFd {
+    for layer in layers {
+        for button in &mut layer.buttons {
+            if button.sticky {
+                button.unlatch(uinput);
+            }
+            if let Some(secondary) = button.secondary.as_deref_mut().filter(|b| b.sticky) {
+                secondary.unlatch(uinput);
+            }
+        }
+    }
+}
+
+// Handles what firing a button does besides sending its key, returns the layer
+// switch the main loop has to apply and sets blank if the backlight has to go off
+fn on_fired<F>(
+    layers: &mut [FunctionLayer], layer: usize, btn: usize, secondary: bool, cfg: &Config,
+    uinput: &mut UInputHandle<F>, overlays: &mut OverlayManager, blank: &mut bool
+) -> Option<LayerAction> where F: AsRawFd {
+    let button = layers[layer].buttons[btn].part_mut(secondary);
+    if cfg.debug_key_overlay {
+        overlays.push("debug-key", button.action.name(), OverlayPriority::Low, Duration::from_millis(DEBUG_KEY_OVERLAY_MS));
+    }
+    let layer_action = match button.action {
+        ButtonAction::Layer(layer_action) => Some(layer_action),
+        ButtonAction::Blank => {
+            *blank = true;
+            None
+        },
+        _ => None
+    };
+    if button.consumes_sticky() {
+        release_sticky(layers, uinput);
+    }
+    layer_action
+}
+
+fn persisted_latches(layers: &[FunctionLayer]) -> Vec<ButtonId> {
+    let mut latched = Vec::new();
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        for (btn, button) in layer.buttons.iter().enumerate() {
+            let parts = [(button, false)].into_iter().chain(button.secondary.as_deref().map(|s| (s, true)));
+            for (part, secondary) in parts {
+                if part.latched && part.persist_latch {
+                    latched.push((layer_idx, btn, secondary));
+                }
+            }
+        }
+    }
+    latched
+}
+
+// Runs BackgroundAction, returns its layer switch for the caller to apply
+// and sets blank like on_fired
+fn on_background_tap<F>(cfg: &Config, uinput: &mut UInputHandle<F>, blank: &mut bool) -> Option<LayerAction> where F: AsRawFd {
+    match cfg.background_action.as_ref()? {
+        // nothing tracks the touch, so the key is tapped rather than held
+        ButtonAction::Key(key) => {
+            toggle_key(uinput, *key, 1);
+            toggle_key(uinput, *key, 0);
+            None
+        },
+        ButtonAction::Layer(layer_action) => Some(*layer_action),
+        ButtonAction::Blank => {
+            *blank = true;
+            None
+        },
+        ButtonAction::Glyph(_) | ButtonAction::Pointer => None
+    }
+}
+
+// Whether reloading from old to new only changes how the buttons look
+fn same_input(old: &[FunctionLayer], new: &[FunctionLayer]) -> bool {
+    old.len() == new.len() && old.iter().zip(new).all(|(old, new)| {
+        old.buttons.len() == new.buttons.len() && old.buttons.iter().zip(&new.buttons).all(|(old, new)| old.same_input(new))
+    })
+}
+
+// Releases every key that is still held, so that nothing stays stuck
+// pressed when the layers are replaced or the daemon exits
+fn release_all<F>(layers: &mut [FunctionLayer], uinput: &mut UInputHandle<F>) where F: AsRawFd {
+    for layer in layers {
+        for button in &mut layer.buttons {
+            button.release(uinput);
+        }
+    }
+}
+
+struct Touch {
+    layer: usize,
+    button: usize,
+    secondary: bool,
+    // whether the touch is within the button and counted in its presses
+    holding: bool,
+    last_update: Instant
+}
+
+// What the touch and key handling work on, kept apart from the libinput loop
+// so that events can be fed to it without the devices
+struct State {
+    layers: [FunctionLayer; 2],
+    touches: HashMap<u32, Touch>,
+    active_layer: usize,
+    // when Fn was last pressed, to tell taps from holds
+    fn_pressed_at: Instant,
+    // FnMode = "Hybrid" keeps the Fn layer after Fn was tapped
+    fn_latched: bool,
+    // when Fn hints started showing, they show while Fn is held
+    fn_hints_since: Option<Instant>,
+    // the time shown by IdleMode = "Clock", if it is showing
+    clock: Option<String>
+}
+
+impl State {
+    fn new(layers: [FunctionLayer; 2]) -> State {
+        State {
+            layers,
+            touches: HashMap::new(),
+            active_layer: 0,
+            fn_pressed_at: Instant::now(),
+            fn_latched: false,
+            fn_hints_since: None,
+            clock: None
+        }
+    }
+    // Switches to another layer or turns the glyph page of the active one,
+    // returns whether the active layer changed
+    fn apply_layer_action<F>(&mut self, action: LayerAction, uinput: &mut UInputHandle<F>) -> bool where F: AsRawFd {
+        match action {
+            LayerAction::NextPage | LayerAction::PreviousPage => {
+                let active_layer = self.active_layer;
+                let layer = &mut self.layers[active_layer];
+                let first_glyph = layer.first_glyph();
+                // the touched glyphs are paged out, their touches end with them
+                self.touches.retain(|_, touch| touch.layer != active_layer || touch.button < first_glyph);
+                layer.turn_page(action == LayerAction::NextPage, uinput);
+                false
+            },
+            LayerAction::NextLayer | LayerAction::PreviousLayer => {
+                self.active_layer = action.apply(self.active_layer, self.layers.len());
+                true
+            }
+        }
+    }
+}
+
+// A touch event in calibrated panel coordinates
+enum TouchInput {
+    Down { slot: u32, x: f64, y: f64 },
+    Motion { slot: u32, x: f64, y: f64 },
+    Up { slot: u32 }
+}
+
+// Presses and releases buttons as touches come and go, returns the layer switch
+// of a button that fired for the caller to apply and sets blank like on_fired
+fn handle_touch<F, P>(
+    bar: &mut State, input: TouchInput, cfg: &Config, width: u16, height: u16,
+    uinput: &mut UInputHandle<F>, pointer: &mut Option<UInputHandle<P>>,
+    overlays: &mut OverlayManager, blank: &mut bool
+) -> Option<LayerAction> where F: AsRawFd, P: AsRawFd {
+    match input {
+        TouchInput::Down { slot, x, y } => {
+            // the touch only wakes the bar from the clock, without a Touch its
+            // motion and up are ignored as well
+            if bar.clock.is_some() {
+                return None;
+            }
+            let geometry = bar.layers[bar.active_layer].geometry(cfg, width as i32);
+            let Some(btn) = geometry.button_at(x).filter(|btn| geometry.hit(*btn, height, x, y)) else {
+                return on_background_tap(cfg, uinput, blank);
+            };
+            let secondary = geometry.in_right_half(btn, x);
+            let button = bar.layers[bar.active_layer].buttons[btn].part_mut(secondary);
+            let fired = button.set_active(uinput, true);
+            bar.touches.insert(slot, Touch {
+                layer: bar.active_layer,
+                button: btn,
+                secondary,
+                holding: button.active,
+                last_update: Instant::now()
+            });
+            if !fired {
+                return None;
+            }
+            if let (ButtonAction::Pointer, Some(pointer)) = (&button.action, pointer.as_mut()) {
+                move_pointer(pointer, &geometry, btn, height, x, y);
+            }
+            on_fired(&mut bar.layers, bar.active_layer, btn, secondary, cfg, uinput, overlays, blank)
+        },
+        TouchInput::Motion { slot, x, y } => {
+            let touch = bar.touches.get_mut(&slot)?;
+            touch.last_update = Instant::now();
+            let geometry = bar.layers[touch.layer].geometry(cfg, width as i32);
+            if let (ButtonAction::Pointer, Some(pointer)) = (&bar.layers[touch.layer].buttons[touch.button].action, pointer.as_mut()) {
+                // the pointer keeps following the touch when it leaves the button
+                move_pointer(pointer, &geometry, touch.button, height, x, y);
+                return None;
+            }
+            let hit = geometry.hit(touch.button, height, x, y);
+            if hit == touch.holding {
+                return None;
+            }
+            // a touch that outlived its layer, e.g. Fn changed while touching, still
+            // ends on the button it started on, but it can not press a hidden button again
+            if hit && touch.layer != bar.active_layer {
+                return None;
+            }
+            let button = bar.layers[touch.layer].buttons[touch.button].part_mut(touch.secondary);
+            let fired = button.set_active(uinput, hit);
+            touch.holding = button.active && hit;
+            if !fired {
+                return None;
+            }
+            let (layer, btn, secondary) = (touch.layer, touch.button, touch.secondary);
+            on_fired(&mut bar.layers, layer, btn, secondary, cfg, uinput, overlays, blank)
+        },
+        TouchInput::Up { slot } => {
+            let touch = bar.touches.remove(&slot)?;
+            if touch.holding {
+                bar.layers[touch.layer].buttons[touch.button].part_mut(touch.secondary).set_active(uinput, false);
+            }
+            None
+        }
+    }
+}
+
+// Releases the buttons of touches whose up event got lost, returns the time
+// until the next remaining touch times out
+fn release_stuck_touches<F>(bar: &mut State, cfg: &Config, uinput: &mut UInputHandle<F>) -> i32 where F: AsRawFd {
+    let mut next_timeout_ms = i32::MAX;
+    if cfg.stuck_touch_timeout.is_zero() {
+        return next_timeout_ms;
+    }
+    bar.touches.retain(|_, touch| {
+        let remaining = cfg.stuck_touch_timeout.saturating_sub(touch.last_update.elapsed());
+        if remaining.is_zero() {
+            if touch.holding {
+                bar.layers[touch.layer].buttons[touch.button].part_mut(touch.secondary).set_active(uinput, false);
+            }
+            return false;
+        }
+        next_timeout_ms = min(next_timeout_ms, remaining.as_millis() as i32);
+        true
+    });
+    next_timeout_ms
+}
+
+// A key event of a keyboard other than the virtual one of the bar
+struct KeyInput<'a> {
+    device: &'a str,
+    key: u32,
+    pressed: bool
+}
+
+// What the main loop has to do after an event
+#[derive(Default)]
+struct Outcome {
+    redraw: bool,
+    // the layer changed for good, rather than only while Fn is held
+    save_layer: bool,
+    // a Blank button fired
+    blank: bool
+}
+
+// Switches layers on Fn and the layer keys, and flashes the buttons of mirrored keys
+fn handle_key(bar: &mut State, input: KeyInput, cfg: &Config) -> Outcome {
+    let mut outcome = Outcome::default();
+    let layer_keys = cfg.layer_keys(input.device);
+    if Some(input.key) == layer_keys.fn_key.map(|k| k as u32) {
+        let new_layer = if input.pressed {
+            bar.fn_pressed_at = Instant::now();
+            if !cfg.fn_hints.is_zero() && bar.active_layer != 1 {
+                // show what the buttons turn into before switching
+                bar.fn_hints_since = Some(bar.fn_pressed_at);
+                outcome.redraw = true;
+                bar.active_layer
+            } else {
+                1
+            }
+        } else {
+            if bar.fn_hints_since.take().is_some() {
+                outcome.redraw = true;
+            }
+            let tapped = bar.fn_pressed_at.elapsed() < cfg.fn_tap_threshold;
+            bar.fn_latched = cfg.fn_mode == FnMode::Hybrid && tapped && !bar.fn_latched;
+            outcome.save_layer = true;
+            if bar.fn_latched { 1 } else { 0 }
+        };
+        if bar.active_layer != new_layer {
+            bar.active_layer = new_layer;
+            outcome.redraw = true;
+        }
+    } else if input.pressed {
+        let layer_action = if Some(input.key) == layer_keys.next_layer_key.map(|k| k as u32) {
+            Some(LayerAction::NextLayer)
+        } else if Some(input.key) == layer_keys.previous_layer_key.map(|k| k as u32) {
+            Some(LayerAction::PreviousLayer)
+        } else {
+            None
+        };
+        if let Some(layer_action) = layer_action {
+            bar.active_layer = layer_action.apply(bar.active_layer, bar.layers.len());
+            outcome.save_layer = true;
+            outcome.redraw = true;
+        }
+        if cfg.mirror_keyboard {
+            for button in &mut bar.layers[bar.active_layer].buttons {
+                button.flash_key(input.key);
+            }
+        }
+    }
+    outcome
+}
+
+// handle_touch, followed by the layer switch of a button that fired
+fn apply_touch<F, P>(
+    bar: &mut State, input: TouchInput, cfg: &Config, width: u16, height: u16,
+    uinput: &mut UInputHandle<F>, pointer: &mut Option<UInputHandle<P>>, overlays: &mut OverlayManager
+) -> Outcome where F: AsRawFd, P: AsRawFd {
+    let mut outcome = Outcome::default();
+    if let Some(layer_action) = handle_touch(bar, input, cfg, width, height, uinput, pointer, overlays, &mut outcome.blank) {
+        outcome.save_layer = bar.apply_layer_action(layer_action, uinput);
+        outcome.redraw = true;
+    }
+    outcome
+}
 
-    // drop privileges to input and video group
-    let groups = ["input", "video"];
+// Runs the timers of Fn hints, touches and buttons, returns the time until the next one is due
+fn update_buttons<F>(bar: &mut State, cfg: &Config, uinput: &mut UInputHandle<F>, overlays: &mut OverlayManager) -> (Outcome, i32) where F: AsRawFd {
+    let mut outcome = Outcome::default();
+    let mut next_timeout_ms = i32::MAX;
+    if let Some(since) = bar.fn_hints_since {
+        let remaining = cfg.fn_hints.saturating_sub(since.elapsed());
+        if remaining.is_zero() {
+            bar.fn_hints_since = None;
+            bar.active_layer = 1;
+            outcome.redraw = true;
+        } else {
+            next_timeout_ms = min(next_timeout_ms, remaining.as_millis() as i32);
+        }
+    }
+    next_timeout_ms = min(next_timeout_ms, release_stuck_touches(bar, cfg, uinput));
+    for layer in &mut bar.layers {
+        for button in &mut layer.buttons {
+            next_timeout_ms = min(next_timeout_ms, button.update_timers(cfg));
+            next_timeout_ms = min(next_timeout_ms, button.update_repeat(uinput, cfg));
+        }
+    }
+    // buttons with a dwell fire here rather than in the touch handlers
+    'dwell: for layer in 0..bar.layers.len() {
+        for btn in 0..bar.layers[layer].buttons.len() {
+            for secondary in [false, true] {
+                if secondary && bar.layers[layer].buttons[btn].secondary.is_none() {
+                    continue;
+                }
+                if !bar.layers[layer].buttons[btn].part_mut(secondary).update_dwell(uinput) {
+                    continue;
+                }
+                if let Some(layer_action) = on_fired(&mut bar.layers, layer, btn, secondary, cfg, uinput, overlays, &mut outcome.blank) {
+                    outcome.save_layer = bar.apply_layer_action(layer_action, uinput);
+                    outcome.redraw = true;
+                    // a page turn changes the buttons, the remaining ones are looked at next time
+                    next_timeout_ms = 0;
+                    break 'dwell;
+                }
+            }
+        }
+    }
+    (outcome, next_timeout_ms)
+}
 
+// Draws onto surface what changed since the last frame, overlay takes the whole bar.
+// Returns the regions to copy to the display, None if nothing changed
+fn draw_frame(
+    bar: &mut State, cfg: &Config, overlay: Option<&str>, enabled: bool, width: u16, height: u16,
+    surface: &Surface, shift: (f64, f64), complete_redraw: bool
+) -> Option<Vec<ClipRect>> {
+    let needs_redraw = match overlay {
+        _ if !enabled => complete_redraw,
+        Some(_) => complete_redraw,
+        None => complete_redraw || bar.layers[bar.active_layer].buttons.iter().any(|b| b.needs_redraw())
+    };
+    if !needs_redraw {
+        return None;
+    }
+    let clips = if !enabled {
+        draw_blank(width as i32, height as i32, surface)
+    } else if let Some(text) = overlay {
+        draw_overlay(cfg, &bar.layers[bar.active_layer].background, text, width as i32, height as i32, surface)
+    } else {
+        let hints = bar.fn_hints_since.map(|_| bar.layers[1].buttons.iter().map(Button::label).collect::<Vec<_>>());
+        bar.layers[bar.active_layer].draw(cfg, width as i32, height as i32, surface, shift, complete_redraw, hints.as_deref())
+    };
+    Some(clips)
+}
+
+// Retries f until it succeeds or the startup grace period is over,
+// as devices may not be ready yet when started early during boot
+fn retry_during_startup<T, E: Display>(what: &str, grace: Duration, mut f: impl FnMut() -> Result<T, E>) -> T {
+    let started = Instant::now();
+    loop {
+        match f() {
+            Ok(value) => return value,
+            Err(e) if started.elapsed() < grace => {
+                eprintln!("Failed to {}, retrying: {}", what, e);
+                thread::sleep(Duration::from_millis(STARTUP_RETRY_INTERVAL_MS));
+            },
+            Err(e) => panic!("Failed to {}: {}", what, e)
+        }
+    }
+}
+
+fn drop_privileges(user: &str, groups: &[String]) {
+    if !matches!(User::from_name(user), Ok(Some(_))) {
+        panic!("Failed to drop privileges: user {} does not exist", user);
+    }
+    let missing: Vec<&str> = groups.iter()
+        .map(String::as_str)
+        .filter(|group| !matches!(Group::from_name(group), Ok(Some(_))))
+        .collect();
+    if !missing.is_empty() {
+        panic!("Failed to drop privileges: group(s) {} do not exist, check PrivDropGroups", missing.join(", "));
+    }
     PrivDrop::default()
-        .user("nobody")
-        .group_list(&groups)
+        .user(user)
+        .group_list(groups)
         .apply()
         .unwrap_or_else(|e| { panic!("Failed to drop privileges: {}", e) });
+}
 
-    let mut surface = ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
-    let mut active_layer = 0;
-    let mut needs_complete_redraw = true;
+// The surface is copied into the framebuffer as is, row by row, so both need the
+// same layout and must fit the rotated bar, which is width long and height tall
+fn check_surface_layout(surface: &ImageSurface, fb: &framebuffer::Info, width: u16, height: u16) -> Result<()> {
+    let (fb_width, fb_height) = fb.size();
+    if fb.bpp() != 32 {
+        return Err(anyhow!("framebuffer has {} bits per pixel, expected 32", fb.bpp()));
+    }
+    if fb.pitch() != surface.stride() as u32 {
+        return Err(anyhow!("framebuffer pitch {} does not match the surface stride {}", fb.pitch(), surface.stride()));
+    }
+    if fb_width < height as u32 || fb_height < width as u32 {
+        return Err(anyhow!("framebuffer {}x{} is smaller than the {}x{} display", fb_width, fb_height, height, width));
+    }
+    if (surface.width() as u32, surface.height() as u32) != (fb_width, fb_height) {
+        return Err(anyhow!(
+            "surface {}x{} does not match the framebuffer {}x{}",
+            surface.width(), surface.height(), fb_width, fb_height
+        ));
+    }
+    Ok(())
+}
 
-    let mut input_tb = Libinput::new_with_udev(Interface);
-    let mut input_main = Libinput::new_with_udev(Interface);
-    input_tb.udev_assign_seat("seat-touchbar").unwrap();
-    input_main.udev_assign_seat("seat0").unwrap();
-    let fd_tb = input_tb.as_fd().try_clone_to_owned().unwrap();
-    let fd_main = input_main.as_fd().try_clone_to_owned().unwrap();
-    let pollfd_tb = PollFd::new(&fd_tb, PollFlags::POLLIN);
-    let pollfd_main = PollFd::new(&fd_main, PollFlags::POLLIN);
-    uinput.set_evbit(EventKind::Key).unwrap();
-    for layer in &layers {
-        for button in &layer.buttons {
-            uinput.set_keybit(button.action).unwrap();
+fn open_uinput() -> File {
+    let started = Instant::now();
+    loop {
+        match OpenOptions::new().write(true).open("/dev/uinput") {
+            Ok(file) => return file,
+            Err(e) if e.kind() == ErrorKind::NotFound && started.elapsed() < Duration::from_millis(UINPUT_WAIT_MS) => {
+                thread::sleep(Duration::from_millis(100));
+            },
+            Err(e) => {
+                let hint = match e.kind() {
+                    ErrorKind::NotFound => "is the uinput module loaded? Try modprobe uinput",
+                    ErrorKind::PermissionDenied => "tiny-dfr needs to be started as root",
+                    _ => "is uinput enabled in the kernel config?"
+                };
+                eprintln!("Failed to open /dev/uinput: {} ({})", e, hint);
+                std::process::exit(1);
+            }
         }
     }
-    let inotify_fd = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
-    let mut cfg_watch_desc = arm_inotify(&inotify_fd);
-    let pollfd_notify = PollFd::new(&inotify_fd, PollFlags::POLLIN);
+}
+
+fn create_device<F>(uinput: &mut UInputHandle<F>, name: &str) where F: AsRawFd {
     let mut dev_name_c = [0 as c_char; 80];
-    let dev_name = "Dynamic Function Row Virtual Input Device".as_bytes();
+    let dev_name = name.as_bytes();
     for i in 0..dev_name.len() {
         dev_name_c[i] = dev_name[i] as c_char;
     }
@@ -464,9 +2298,120 @@ fn real_main(drm: &mut DrmBackend) {
         name: dev_name_c
     }).unwrap();
     uinput.dev_create().unwrap();
+}
+
+// Pointer buttons get their own device, a keyboard with absolute axes would
+// not be recognized as a pointer
+fn create_pointer() -> UInputHandle<File> {
+    let mut pointer = UInputHandle::new(open_uinput());
+    pointer.set_evbit(EventKind::Absolute).unwrap();
+    for axis in [AbsoluteAxis::X, AbsoluteAxis::Y] {
+        pointer.set_absbit(axis).unwrap();
+        pointer.abs_setup(&uinput_abs_setup {
+            code: axis as u16,
+            absinfo: input_absinfo {
+                value: 0,
+                minimum: 0,
+                maximum: POINTER_ABS_MAX,
+                fuzz: 0,
+                flat: 0,
+                resolution: 0
+            }
+        }).unwrap();
+    }
+    // never pressed, but absolute devices without buttons are not classified as mice by udev
+    pointer.set_evbit(EventKind::Key).unwrap();
+    pointer.set_keybit(Key::ButtonLeft).unwrap();
+    create_device(&mut pointer, VIRTUAL_POINTER_NAME);
+    pointer
+}
+
+// calibrate prints the coordinates of every tap, to help derive TouchCalibration,
+// no_drop_privs overrides DropPrivileges
+fn real_main(drm: &mut DrmBackend, base: ConfigProxy, calibrate: bool, no_drop_privs: bool) {
+    let (height, width) = drm.mode().size();
+    let (db_width, db_height) = drm.fb_info().unwrap().size();
+    let mut uinput = UInputHandle::new(open_uinput());
+    let mut backlight = BacklightManager::new();
+    let (mut cfg, layers) = build_config(base, width, height, drm.orientation(), load_font);
+    let mut bar = State::new(layers);
+    // like the keys of the virtual keyboard, this is only set up at startup
+    let has_pointer = bar.layers.iter().flat_map(|layer| &layer.buttons).any(|button| matches!(button.action, ButtonAction::Pointer));
+    let mut pointer = if has_pointer { Some(create_pointer()) } else { None };
+    let mut pixel_shift = PixelShiftManager::new();
+    let mut overlays = OverlayManager::new();
+    let mut state = StateManager::new();
+    let mut power = PowerManager::new();
+    cfg.power = if power.on_ac() { cfg.ac_power } else { cfg.battery_power };
+
+    if no_drop_privs {
+        eprintln!("WARNING: --no-drop-privs was given, tiny-dfr keeps running as root. Do not use this outside of testing");
+    } else if cfg.drop_privileges {
+        drop_privileges(&cfg.priv_drop_user, &cfg.priv_drop_groups);
+    } else {
+        eprintln!("Warning: DropPrivileges is disabled, tiny-dfr keeps running as root");
+    }
+
+    let mut surface = ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
+    if let Err(e) = check_surface_layout(&surface, &drm.fb_info().unwrap(), width, height) {
+        eprintln!("Unsupported framebuffer layout: {}", e);
+        std::process::exit(1);
+    }
+    bar.active_layer = state.get().active_layer.min(bar.layers.len() - 1);
+    bar.fn_latched = state.get().fn_latched;
+    let mut last_active_layer = bar.active_layer;
+    let mut needs_complete_redraw = true;
+    let mut resumed_at: Option<Instant> = None;
+    let mut enabled = true;
+    let mut warned_short_map = false;
+    // frames drawn since fps_since, for DebugDrawTiming
+    let mut frame_count = 0;
+    let mut fps_since = Instant::now();
+
+    let mut input_tb = Libinput::new_with_udev(Interface);
+    let mut input_main = Libinput::new_with_udev(Interface);
+    retry_during_startup("assign seat-touchbar", cfg.startup_grace, || input_tb.udev_assign_seat("seat-touchbar").map_err(|_| "seat not available"));
+    retry_during_startup("assign seat0", cfg.startup_grace, || input_main.udev_assign_seat("seat0").map_err(|_| "seat not available"));
+    let fd_tb = input_tb.as_fd().try_clone_to_owned().unwrap();
+    let fd_main = input_main.as_fd().try_clone_to_owned().unwrap();
+    let pollfd_tb = PollFd::new(&fd_tb, PollFlags::POLLIN);
+    let pollfd_main = PollFd::new(&fd_main, PollFlags::POLLIN);
+    uinput.set_evbit(EventKind::Key).unwrap();
+    for layer in &bar.layers {
+        for button in &layer.buttons {
+            for key in button.keys() {
+                uinput.set_keybit(key).unwrap();
+            }
+        }
+    }
+    if let Some(ButtonAction::Key(key)) = cfg.background_action {
+        uinput.set_keybit(key).unwrap();
+    }
+    let inotify_fd = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
+    let mut cfg_watch_desc = arm_inotify(&inotify_fd);
+    let pollfd_notify = PollFd::new(&inotify_fd, PollFlags::POLLIN);
+    let mut term_signals = SigSet::empty();
+    term_signals.add(Signal::SIGTERM);
+    term_signals.add(Signal::SIGINT);
+    // toggles the bar on and off
+    term_signals.add(Signal::SIGUSR1);
+    term_signals.thread_block().unwrap();
+    let mut signal_fd = SignalFd::with_flags(&term_signals, SfdFlags::SFD_NONBLOCK).unwrap();
+    let fd_signal = signal_fd.as_fd().try_clone_to_owned().unwrap();
+    let pollfd_signal = PollFd::new(&fd_signal, PollFlags::POLLIN);
+    create_device(&mut uinput, VIRTUAL_DEVICE_NAME);
+    // the buttons may have changed since the state was saved, skip the ones that are gone
+    for &(layer, btn, secondary) in &state.get().latched {
+        let Some(button) = bar.layers.get_mut(layer).and_then(|l| l.buttons.get_mut(btn)) else {
+            continue;
+        };
+        if secondary && button.secondary.is_none() {
+            continue;
+        }
+        button.part_mut(secondary).restore_latch(&mut uinput);
+    }
 
     let mut digitizer: Option<InputDevice> = None;
-    let mut touches = HashMap::new();
     loop {
         let evts = match inotify_fd.read_events() {
             Ok(e) => e,
@@ -477,13 +2422,37 @@ fn real_main(drm: &mut DrmBackend) {
             if evt.wd != cfg_watch_desc {
                 continue
             }
-            (cfg, layers) = load_config(width);
-            active_layer = 0;
+            let (new_cfg, mut new_layers) = reload_config(width, height, drm.orientation());
+            if same_input(&bar.layers, &new_layers) {
+                // only the looks changed, keep the layer, the touches and whatever is held or latched
+                for (new, old) in new_layers.iter_mut().zip(&bar.layers) {
+                    for (new, old) in new.buttons.iter_mut().zip(&old.buttons) {
+                        new.take_state_from(old);
+                    }
+                }
+            } else {
+                release_all(&mut bar.layers, &mut uinput);
+                bar.touches.clear();
+                bar.active_layer = 0;
+                bar.fn_latched = false;
+                bar.fn_hints_since = None;
+                state.save_layer(bar.active_layer, bar.fn_latched);
+            }
+            (cfg, bar.layers) = (new_cfg, new_layers);
+            cfg.power = if power.on_ac() { cfg.ac_power } else { cfg.battery_power };
             needs_complete_redraw = true;
             cfg_watch_desc = arm_inotify(&inotify_fd);
         }
 
-        let mut next_timeout_ms = TIMEOUT_MS;
+        let mut next_timeout_ms = min(TIMEOUT_MS, backlight.next_timeout_ms(&cfg));
+        if cfg.ac_power != cfg.battery_power {
+            let (power_changed, power_next_timeout_ms) = power.update();
+            if power_changed {
+                cfg.power = if power.on_ac() { cfg.ac_power } else { cfg.battery_power };
+                needs_complete_redraw = true;
+            }
+            next_timeout_ms = min(next_timeout_ms, power_next_timeout_ms);
+        }
         if cfg.enable_pixel_shift {
             let (pixel_shift_needs_redraw, pixel_shift_next_timeout_ms) = pixel_shift.update();
             if pixel_shift_needs_redraw {
@@ -491,26 +2460,126 @@ fn real_main(drm: &mut DrmBackend) {
             }
             next_timeout_ms = min(next_timeout_ms, pixel_shift_next_timeout_ms);
         }
-
-        if needs_complete_redraw || layers[active_layer].buttons.iter().any(|b| b.changed) {
-            let shift = if cfg.enable_pixel_shift {
-                pixel_shift.get()
+        if bar.active_layer != last_active_layer {
+            last_active_layer = bar.active_layer;
+            match &bar.layers[bar.active_layer].name {
+                Some(name) if !cfg.layer_name_overlay.is_zero() => {
+                    overlays.push("layer-name", name.clone(), OverlayPriority::Normal, cfg.layer_name_overlay);
+                },
+                _ => {}
+            }
+        }
+        let (overlays_changed, overlays_next_timeout_ms) = overlays.update();
+        if overlays_changed {
+            needs_complete_redraw = true;
+        }
+        next_timeout_ms = min(next_timeout_ms, overlays_next_timeout_ms);
+        if let Some(resumed) = resumed_at {
+            // keep repainting for a while, the framebuffer may get reinitialized after resume
+            if resumed.elapsed() < cfg.resume_redraw {
+                needs_complete_redraw = true;
+                next_timeout_ms = min(next_timeout_ms, RESUME_REDRAW_INTERVAL_MS);
             } else {
-                (0.0, 0.0)
-            };
-            let clips = layers[active_layer].draw(&cfg, width as i32, height as i32, &surface, shift, needs_complete_redraw);
+                resumed_at = None;
+            }
+        }
+        let (outcome, buttons_next_timeout_ms) = update_buttons(&mut bar, &cfg, &mut uinput, &mut overlays);
+        next_timeout_ms = min(next_timeout_ms, buttons_next_timeout_ms);
+        if outcome.save_layer {
+            state.save_layer(bar.active_layer, bar.fn_latched);
+        }
+        if outcome.redraw {
+            needs_complete_redraw = true;
+        }
+        if outcome.blank {
+            backlight.blank();
+        }
+
+        let clock = if cfg.idle_mode == IdleMode::Clock && backlight.is_idle(&cfg) {
+            let (text, next_minute_ms) = clock_text();
+            next_timeout_ms = min(next_timeout_ms, next_minute_ms);
+            Some(text)
+        } else {
+            None
+        };
+        if clock != bar.clock {
+            bar.clock = clock.clone();
+            needs_complete_redraw = true;
+        }
+        // the clock takes the whole bar like an overlay
+        let overlay = clock.as_deref().or(overlays.current());
+        let shift = if cfg.enable_pixel_shift {
+            pixel_shift.get()
+        } else {
+            (0.0, 0.0)
+        };
+        let draw_start = Instant::now();
+        if let Some(clips) = draw_frame(&mut bar, &cfg, overlay, enabled, width, height, &surface, shift, needs_complete_redraw) {
+            let copy_start = Instant::now();
             let data = surface.data().unwrap();
-            drm.map().unwrap().as_mut()[..data.len()].copy_from_slice(&data);
+            let mut map = drm.map().unwrap();
+            let fb = map.as_mut();
+            // some drivers map less than they report, leave the rest of the bar stale rather than crash
+            let len = min(data.len(), fb.len());
+            if len < data.len() && !warned_short_map {
+                eprintln!("Warning: the framebuffer mapping is {} bytes, {} expected, the end of the bar will not be drawn", fb.len(), data.len());
+                warned_short_map = true;
+            }
+            fb[..len].copy_from_slice(&data[..len]);
+            drop(map);
+            let dirty_start = Instant::now();
             drm.dirty(&clips).unwrap();
+            if cfg.debug_draw_timing {
+                eprintln!(
+                    "Frame timing: draw {:?}, copy {:?}, dirty {:?} ({} clips{})",
+                    copy_start - draw_start, dirty_start - copy_start, dirty_start.elapsed(),
+                    clips.len(), if needs_complete_redraw { ", complete redraw" } else { "" }
+                );
+                frame_count += 1;
+                let elapsed = fps_since.elapsed();
+                if elapsed >= Duration::from_secs(1) {
+                    let limit = match cfg.power.min_frame_interval {
+                        Duration::ZERO => "unlimited".to_string(),
+                        interval => format!("at most {:.0}", 1.0 / interval.as_secs_f64())
+                    };
+                    eprintln!("Drawing {:.1} frames per second ({})", frame_count as f64 / elapsed.as_secs_f64(), limit);
+                    frame_count = 0;
+                    fps_since = Instant::now();
+                }
+            }
             needs_complete_redraw = false;
         }
 
-        poll(&mut [pollfd_tb, pollfd_main, pollfd_notify], next_timeout_ms).unwrap();
+        poll(&mut [pollfd_tb, pollfd_main, pollfd_notify, pollfd_signal], next_timeout_ms).unwrap();
+        match signal_fd.read_signal().unwrap() {
+            Some(info) if info.ssi_signo == Signal::SIGUSR1 as u32 => {
+                enabled = !enabled;
+                backlight.set_enabled(enabled);
+                if !enabled {
+                    release_all(&mut bar.layers, &mut uinput);
+                    bar.touches.clear();
+                }
+                needs_complete_redraw = true;
+            },
+            Some(_) => {
+                release_all(&mut bar.layers, &mut uinput);
+                return;
+            },
+            None => {}
+        }
         input_tb.dispatch().unwrap();
         input_main.dispatch().unwrap();
         for event in &mut input_tb.clone().chain(input_main.clone()) {
             backlight.process_event(&event);
             match event {
+                Event::Switch(SwitchEvent::Toggle(toggle)) => {
+                    if toggle.switch() == Some(Switch::Lid) && toggle.switch_state() == SwitchState::Off {
+                        needs_complete_redraw = true;
+                        if !cfg.resume_redraw.is_zero() {
+                            resumed_at = Some(Instant::now());
+                        }
+                    }
+                },
                 Event::Device(DeviceEvent::Added(evt)) => {
                     let dev = evt.device();
                     if dev.name().contains(" Touch Bar") {
@@ -518,55 +2587,758 @@ fn real_main(drm: &mut DrmBackend) {
                     }
                 },
                 Event::Keyboard(KeyboardEvent::Key(key)) => {
-                    if key.key() == Key::Fn as u32 {
-                        let new_layer = match key.key_state() {
-                            KeyState::Pressed => 1,
-                            KeyState::Released => 0
-                        };
-                        if active_layer != new_layer {
-                            active_layer = new_layer;
-                            needs_complete_redraw = true;
-                        }
+                    // keys sent by the bar itself come back through seat0 as well,
+                    // e.g. an Fn modifier must not switch layers
+                    if key.device().name() == VIRTUAL_DEVICE_NAME {
+                        continue;
+                    }
+                    let device = key.device();
+                    let input = KeyInput { device: device.name(), key: key.key(), pressed: key.key_state() == KeyState::Pressed };
+                    let outcome = handle_key(&mut bar, input, &cfg);
+                    if outcome.save_layer {
+                        state.save_layer(bar.active_layer, bar.fn_latched);
+                    }
+                    if outcome.redraw {
+                        needs_complete_redraw = true;
                     }
                 },
                 Event::Touch(te) => {
-                    if Some(te.device()) != digitizer || backlight.current_bl() == 0 {
+                    if Some(te.device()) != digitizer || backlight.current_bl() == 0 || !enabled {
                         continue
                     }
-                    match te {
+                    let input = match te {
                         TouchEvent::Down(dn) => {
-                            let x = dn.x_transformed(width as u32);
-                            let y = dn.y_transformed(height as u32);
-                            let btn = (x / (width as f64 / layers[active_layer].buttons.len() as f64)) as u32;
-                            if button_hit(layers[active_layer].buttons.len() as u32, btn, width, height, x, y) {
-                                touches.insert(dn.seat_slot(), (active_layer, btn));
-                                layers[active_layer].buttons[btn as usize].set_active(&mut uinput, true);
+                            let (raw_x, raw_y) = (dn.x_transformed(width as u32), dn.y_transformed(height as u32));
+                            let (x, y) = cfg.touch_calibration.apply(raw_x, raw_y);
+                            if calibrate {
+                                println!("Tap at x = {:.1}, y = {:.1} (calibrated x = {:.1}, y = {:.1})", raw_x, raw_y, x, y);
                             }
+                            TouchInput::Down { slot: dn.seat_slot(), x, y }
                         },
                         TouchEvent::Motion(mtn) => {
-                            if !touches.contains_key(&mtn.seat_slot()) {
-                                continue;
-                            }
-
-                            let x = mtn.x_transformed(width as u32);
-                            let y = mtn.y_transformed(height as u32);
-                            let (layer, btn) = *touches.get(&mtn.seat_slot()).unwrap();
-                            let hit = button_hit(layers[layer].buttons.len() as u32, btn, width, height, x, y);
-                            layers[layer].buttons[btn as usize].set_active(&mut uinput, hit);
+                            let (x, y) = cfg.touch_calibration.apply(mtn.x_transformed(width as u32), mtn.y_transformed(height as u32));
+                            TouchInput::Motion { slot: mtn.seat_slot(), x, y }
                         },
-                        TouchEvent::Up(up) => {
-                            if !touches.contains_key(&up.seat_slot()) {
-                                continue;
-                            }
-                            let (layer, btn) = *touches.get(&up.seat_slot()).unwrap();
-                            layers[layer].buttons[btn as usize].set_active(&mut uinput, false);
-                        }
-                        _ => {}
+                        TouchEvent::Up(up) => TouchInput::Up { slot: up.seat_slot() },
+                        _ => continue
+                    };
+                    let outcome = apply_touch(&mut bar, input, &cfg, width, height, &mut uinput, &mut pointer, &mut overlays);
+                    if outcome.save_layer {
+                        state.save_layer(bar.active_layer, bar.fn_latched);
+                    }
+                    if outcome.redraw {
+                        needs_complete_redraw = true;
+                    }
+                    if outcome.blank {
+                        backlight.blank();
                     }
                 },
                 _ => {}
             }
         }
-        backlight.update_backlight();
+        backlight.update_backlight(&cfg, bar.layers[bar.active_layer].brightness);
+        state.save_latched(persisted_latches(&bar.layers));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Read,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering}
+    };
+    use cairo::{FontSlant, FontWeight};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    pub(crate) const WIDTH: u16 = 2008;
+    pub(crate) const HEIGHT: u16 = 60;
+
+    // Text only, icons would have to be installed
+    const TEST_CFG: &str = r#"
+        PrimaryLayerKeys = [
+            { Text = "F1", Action = "F1" },
+            { Text = "F2", Action = "F2" },
+            { Text = "Mute", Action = "Mute" },
+            { Text = "Blank", Action = "Blank" }
+        ]
+        MediaLayerKeys = [
+            { Text = "Play", Action = "PlayPause" },
+            { Text = "Prev", Action = "PreviousSong", Secondary = { Text = "Next", Action = "NextSong" } },
+            { Text = "Down", Action = "VolumeDown" },
+            { Text = "Up", Action = "VolumeUp" }
+        ]
+    "#;
+
+    // cairo's builtin font, so that no fonts have to be installed
+    fn builtin_font(_name: &str) -> Font {
+        Font {
+            face: FontFace::toy_create("@cairo:", FontSlant::Normal, FontWeight::Normal).unwrap(),
+            ft_face: None
+        }
+    }
+
+    // TEST_CFG and then user merged over the defaults, like user configs
+    pub(crate) fn test_bar(user: &str) -> (Config, State) {
+        let mut base = ConfigProxy::parse(include_str!("../share/tiny-dfr/config.toml")).unwrap();
+        base.merge(ConfigProxy::parse(TEST_CFG).unwrap());
+        base.merge(ConfigProxy::parse(user).unwrap());
+        let (cfg, layers) = build_config(base, WIDTH, HEIGHT, None, builtin_font);
+        (cfg, State::new(layers))
+    }
+
+    // Where a touch lands on button btn of the active layer, right_half picks the secondary
+    fn on_button(bar: &State, cfg: &Config, btn: usize, right_half: bool) -> (f64, f64) {
+        let geometry = bar.layers[bar.active_layer].geometry(cfg, WIDTH as i32);
+        let quarter = geometry.button_width / 4.0;
+        let x = geometry.left_edge(btn) + if right_half { 3.0 * quarter } else { quarter };
+        (x, HEIGHT as f64 / 2.0)
+    }
+
+    static NEXT_FAKE: AtomicUsize = AtomicUsize::new(0);
+
+    // A virtual keyboard writing to a plain file, so that the key events sent can be read back
+    pub(crate) struct FakeUinput {
+        pub(crate) handle: UInputHandle<File>,
+        path: PathBuf,
+        read: usize
+    }
+
+    impl FakeUinput {
+        pub(crate) fn new() -> FakeUinput {
+            let path = env::temp_dir().join(format!("tiny-dfr-test-{}-{}", std::process::id(), NEXT_FAKE.fetch_add(1, Ordering::Relaxed)));
+            let file = OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+            FakeUinput { handle: UInputHandle::new(file), path, read: 0 }
+        }
+        // The key events sent since the last call, as key codes and values
+        pub(crate) fn keys(&mut self) -> Vec<(u16, i32)> {
+            let mut data = Vec::new();
+            File::open(&self.path).unwrap().read_to_end(&mut data).unwrap();
+            let size = std::mem::size_of::<input_event>();
+            let events: Vec<input_event> = data[self.read..].chunks_exact(size)
+                .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const input_event) })
+                .collect();
+            self.read = data.len();
+            events.into_iter()
+                .filter(|event| event.type_ == EventKind::Key as u16)
+                .map(|event| (event.code, event.value))
+                .collect()
+        }
+    }
+
+    impl Drop for FakeUinput {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn touch(bar: &mut State, cfg: &Config, uinput: &mut FakeUinput, input: TouchInput) -> (Option<LayerAction>, bool) {
+        let mut blank = false;
+        let action = handle_touch(
+            bar, input, cfg, WIDTH, HEIGHT, &mut uinput.handle, &mut None::<UInputHandle<File>>,
+            &mut OverlayManager::new(), &mut blank
+        );
+        (action, blank)
+    }
+
+    // The key events sent when button btn of the active layer is touched and when it is lifted
+    fn press_and_lift(bar: &mut State, cfg: &Config, uinput: &mut FakeUinput, btn: usize) -> (Vec<(u16, i32)>, Vec<(u16, i32)>) {
+        let (x, y) = on_button(bar, cfg, btn, false);
+        touch(bar, cfg, uinput, TouchInput::Down { slot: 0, x, y });
+        let pressed = uinput.keys();
+        touch(bar, cfg, uinput, TouchInput::Up { slot: 0 });
+        (pressed, uinput.keys())
+    }
+
+    fn key(code: Key, pressed: bool) -> KeyInput<'static> {
+        KeyInput { device: "Apple Internal Keyboard / Trackpad", key: code as u32, pressed }
+    }
+
+    #[test]
+    fn config_builds_without_system_fonts() {
+        let (cfg, bar) = test_bar("");
+        assert!(cfg.font.has_chars("F1"));
+        assert!(!cfg.font.has_chars("⏯"));
+        assert_eq!(bar.layers[0].buttons.len(), 4);
+        assert_eq!(bar.layers[1].buttons.len(), 4);
+    }
+
+    #[test]
+    fn touch_presses_and_releases_its_button() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 1, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_eq!(uinput.keys(), [(Key::F2 as u16, 1)]);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), [(Key::F2 as u16, 0)]);
+        assert!(bar.touches.is_empty());
+    }
+
+    #[test]
+    fn touch_leaving_its_button_releases_it() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 0, x, y: 0.0 });
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 1), (Key::F1 as u16, 0)]);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), []);
+    }
+
+    #[test]
+    fn blank_is_left_to_the_caller() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 3, false);
+        let (action, blank) = touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert!(action.is_none());
+        assert!(blank);
+        assert_eq!(uinput.keys(), []);
+    }
+
+    #[test]
+    fn background_action_runs_on_taps_between_buttons() {
+        let (cfg, mut bar) = test_bar("BackgroundAction = \"NextLayer\"");
+        let mut uinput = FakeUinput::new();
+        let (action, _) = touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x: 100.0, y: 2.0 });
+        assert_eq!(action, Some(LayerAction::NextLayer));
+    }
+
+    #[test]
+    fn held_fn_shows_the_second_layer_without_saving_it() {
+        let (cfg, mut bar) = test_bar("");
+        let outcome = handle_key(&mut bar, key(Key::Fn, true), &cfg);
+        assert_eq!(bar.active_layer, 1);
+        assert!(outcome.redraw);
+        assert!(!outcome.save_layer);
+        thread::sleep(cfg.fn_tap_threshold);
+        let outcome = handle_key(&mut bar, key(Key::Fn, false), &cfg);
+        assert_eq!(bar.active_layer, 0);
+        assert!(outcome.redraw);
+        assert!(outcome.save_layer);
+        assert!(!bar.fn_latched);
+    }
+
+    #[test]
+    fn tapped_fn_latches_in_hybrid_mode() {
+        let (cfg, mut bar) = test_bar("FnMode = \"Hybrid\"");
+        handle_key(&mut bar, key(Key::Fn, true), &cfg);
+        handle_key(&mut bar, key(Key::Fn, false), &cfg);
+        assert!(bar.fn_latched);
+        assert_eq!(bar.active_layer, 1);
+        handle_key(&mut bar, key(Key::Fn, true), &cfg);
+        handle_key(&mut bar, key(Key::Fn, false), &cfg);
+        assert!(!bar.fn_latched);
+        assert_eq!(bar.active_layer, 0);
+    }
+
+    #[test]
+    fn layer_keys_switch_and_save_the_layer() {
+        let (cfg, mut bar) = test_bar("NextLayerKey = \"F20\"");
+        let outcome = handle_key(&mut bar, key(Key::F20, true), &cfg);
+        assert_eq!(bar.active_layer, 1);
+        assert!(outcome.save_layer);
+        let outcome = handle_key(&mut bar, key(Key::F20, false), &cfg);
+        assert_eq!(bar.active_layer, 1);
+        assert!(!outcome.save_layer && !outcome.redraw);
+    }
+
+    #[test]
+    fn mirrored_keys_flash_their_buttons() {
+        let (cfg, mut bar) = test_bar("MirrorKeyboard = true");
+        handle_key(&mut bar, key(Key::F2, true), &cfg);
+        assert!(bar.layers[0].buttons[1].highlighted());
+        assert!(!bar.layers[0].buttons[0].highlighted());
+    }
+
+    #[test]
+    fn touches_missing_their_up_event_are_released() {
+        let (cfg, mut bar) = test_bar("StuckTouchTimeoutMs = 20");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert!(release_stuck_touches(&mut bar, &cfg, &mut uinput.handle) <= 20);
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 1)]);
+        thread::sleep(cfg.stuck_touch_timeout);
+        assert_eq!(release_stuck_touches(&mut bar, &cfg, &mut uinput.handle), i32::MAX);
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 0)]);
+        assert!(bar.touches.is_empty());
+        // the up event turning up late releases nothing again
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), []);
+    }
+
+    #[test]
+    fn motion_keeps_a_touch_from_timing_out() {
+        let (cfg, mut bar) = test_bar("StuckTouchTimeoutMs = 40");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        thread::sleep(Duration::from_millis(25));
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 0, x: x + 1.0, y });
+        thread::sleep(Duration::from_millis(25));
+        release_stuck_touches(&mut bar, &cfg, &mut uinput.handle);
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 1)]);
+        assert_eq!(bar.touches.len(), 1);
+    }
+
+    #[test]
+    fn touches_do_not_time_out_by_default() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_eq!(release_stuck_touches(&mut bar, &cfg, &mut uinput.handle), i32::MAX);
+        assert_eq!(bar.touches.len(), 1);
+    }
+
+    #[test]
+    fn two_touches_on_one_button_press_it_once() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 1, x: x + 20.0, y });
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 1)]);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), []);
+        assert!(bar.layers[0].buttons[0].active);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 1 });
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 0)]);
+        assert!(!bar.layers[0].buttons[0].active);
+    }
+
+    #[test]
+    fn button_stays_pressed_while_one_of_two_touches_slides_off() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 1, x, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 1, x, y: 0.0 });
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 1)]);
+        // the touch that slid off no longer counts, lifting it changes nothing
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 1 });
+        assert_eq!(uinput.keys(), []);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), [(Key::F1 as u16, 0)]);
+    }
+
+    #[test]
+    fn halves_of_a_split_button_are_held_separately() {
+        let (cfg, mut bar) = test_bar("");
+        bar.active_layer = 1;
+        let mut uinput = FakeUinput::new();
+        let (left, y) = on_button(&bar, &cfg, 1, false);
+        let (right, _) = on_button(&bar, &cfg, 1, true);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x: left, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 1, x: right, y });
+        assert_eq!(uinput.keys(), [(Key::PreviousSong as u16, 1), (Key::NextSong as u16, 1)]);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 1 });
+        assert_eq!(uinput.keys(), [(Key::NextSong as u16, 0)]);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), [(Key::PreviousSong as u16, 0)]);
+    }
+
+    #[test]
+    fn keys_are_held_as_long_as_they_are_touched() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        assert_eq!(press_and_lift(&mut bar, &cfg, &mut uinput, 0), (vec![(Key::F1 as u16, 1)], vec![(Key::F1 as u16, 0)]));
+        bar.active_layer = 1;
+        assert_eq!(
+            press_and_lift(&mut bar, &cfg, &mut uinput, 3),
+            (vec![(Key::VolumeUp as u16, 1)], vec![(Key::VolumeUp as u16, 0)])
+        );
+    }
+
+    #[test]
+    fn media_toggles_are_tapped_on_touch() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        assert_eq!(
+            press_and_lift(&mut bar, &cfg, &mut uinput, 2),
+            (vec![(Key::Mute as u16, 1), (Key::Mute as u16, 0)], vec![])
+        );
+        bar.active_layer = 1;
+        assert_eq!(
+            press_and_lift(&mut bar, &cfg, &mut uinput, 0),
+            (vec![(Key::PlayPause as u16, 1), (Key::PlayPause as u16, 0)], vec![])
+        );
+    }
+
+    #[test]
+    fn emit_overrides_the_category_default() {
+        let (cfg, mut bar) = test_bar(r#"
+            PrimaryLayerKeys = [
+                { Text = "F1", Action = "F1", Emit = "Tap" },
+                { Text = "Mute", Action = "Mute", Emit = "Hold" }
+            ]
+        "#);
+        let mut uinput = FakeUinput::new();
+        assert_eq!(press_and_lift(&mut bar, &cfg, &mut uinput, 0), (vec![(Key::F1 as u16, 1), (Key::F1 as u16, 0)], vec![]));
+        assert_eq!(press_and_lift(&mut bar, &cfg, &mut uinput, 1), (vec![(Key::Mute as u16, 1)], vec![(Key::Mute as u16, 0)]));
+    }
+
+    #[test]
+    fn repeat_taps_until_lifted() {
+        let (cfg, mut bar) = test_bar(r#"
+            RepeatStartRate = 50.0
+            RepeatEndRate = 50.0
+            PrimaryLayerKeys = [{ Text = "Up", Action = "VolumeUp", Emit = "Repeat" }]
+        "#);
+        let mut uinput = FakeUinput::new();
+        let tap = [(Key::VolumeUp as u16, 1), (Key::VolumeUp as u16, 0)];
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_eq!(uinput.keys(), tap);
+        thread::sleep(Duration::from_millis(25));
+        bar.layers[0].buttons[0].update_repeat(&mut uinput.handle, &cfg);
+        assert_eq!(uinput.keys(), tap);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        thread::sleep(Duration::from_millis(25));
+        assert_eq!(bar.layers[0].buttons[0].update_repeat(&mut uinput.handle, &cfg), i32::MAX);
+        assert_eq!(uinput.keys(), []);
+    }
+
+    #[test]
+    fn latching_buttons_ignore_emit() {
+        let (cfg, mut bar) = test_bar(r#"
+            PrimaryLayerKeys = [{ Text = "Shift", Action = "LeftShift", Latch = true, Emit = "Tap" }]
+        "#);
+        let mut uinput = FakeUinput::new();
+        assert_eq!(press_and_lift(&mut bar, &cfg, &mut uinput, 0), (vec![(Key::LeftShift as u16, 1)], vec![]));
+        assert_eq!(press_and_lift(&mut bar, &cfg, &mut uinput, 0), (vec![(Key::LeftShift as u16, 0)], vec![]));
+    }
+
+    #[test]
+    fn touch_outliving_fn_ends_on_its_own_layer() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        handle_key(&mut bar, key(Key::Fn, true), &cfg);
+        let (x, y) = on_button(&bar, &cfg, 2, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_eq!(uinput.keys(), [(Key::VolumeDown as u16, 1)]);
+        let outcome = handle_key(&mut bar, key(Key::Fn, false), &cfg);
+        // the bar shows the first layer right away, while the touch keeps holding VolumeDown
+        assert_eq!(bar.active_layer, 0);
+        assert!(outcome.redraw);
+        assert!(bar.layers[1].buttons[2].active);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), [(Key::VolumeDown as u16, 0)]);
+        assert!(!bar.layers[1].buttons[2].active);
+        assert!(!bar.layers[0].buttons[2].active);
+    }
+
+    #[test]
+    fn touch_outliving_fn_can_not_press_its_hidden_button_again() {
+        let (cfg, mut bar) = test_bar("");
+        let mut uinput = FakeUinput::new();
+        handle_key(&mut bar, key(Key::Fn, true), &cfg);
+        let (x, y) = on_button(&bar, &cfg, 2, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        handle_key(&mut bar, key(Key::Fn, false), &cfg);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 0, x, y: 0.0 });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 0, x, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), [(Key::VolumeDown as u16, 1), (Key::VolumeDown as u16, 0)]);
+    }
+
+    #[test]
+    fn hold_follows_the_touch() {
+        let (cfg, mut bar) = test_bar(r#"
+            PrimaryLayerKeys = [{ Text = "F5", Action = "F5", Emit = "Hold" }]
+        "#);
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(uinput.keys(), [(Key::F5 as u16, 1)]);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), [(Key::F5 as u16, 0)]);
+    }
+
+    #[test]
+    fn pulse_taps_once_however_long_it_is_held() {
+        let (cfg, mut bar) = test_bar(r#"
+            PrimaryLayerKeys = [{ Text = "F5", Action = "F5", Emit = "Pulse" }]
+        "#);
+        assert!(bar.layers[0].buttons[0].emit_mode == EmitMode::Tap);
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 0, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_eq!(uinput.keys(), [(Key::F5 as u16, 1), (Key::F5 as u16, 0)]);
+        thread::sleep(Duration::from_millis(30));
+        bar.layers[0].buttons[0].update_repeat(&mut uinput.handle, &cfg);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), []);
+    }
+
+    pub(crate) fn pixels(surface: &mut ImageSurface) -> Vec<u8> {
+        surface.flush();
+        surface.data().unwrap().to_vec()
+    }
+
+    #[test]
+    fn partial_redraws_leave_no_stale_pixels() {
+        for orientation in ["RightSideUp", "LeftSideUp"] {
+            // the inset makes the buttons a fractional number of pixels wide
+            let (cfg, mut bar) = test_bar(&format!("PressFlashMs = 0\nLeftInset = 3\nPanelOrientation = \"{}\"", orientation));
+            assert!(bar.layers[0].geometry(&cfg, WIDTH as i32).button_width.fract() != 0.0);
+            // and pixel shift moves their edges between pixels
+            let shift = (1.5, 0.5);
+            let mut uinput = FakeUinput::new();
+            let mut surface = ImageSurface::create(Format::ARgb32, HEIGHT as i32, WIDTH as i32).unwrap();
+            let stride = surface.stride() as usize;
+            bar.layers[0].draw(&cfg, WIDTH as i32, HEIGHT as i32, &surface, shift, true, None);
+            let (x, y) = on_button(&bar, &cfg, 1, false);
+            for input in [TouchInput::Down { slot: 0, x, y }, TouchInput::Up { slot: 0 }] {
+                touch(&mut bar, &cfg, &mut uinput, input);
+                // ends the highlight of the release
+                bar.layers[0].buttons[1].update_timers(&cfg);
+                let before = pixels(&mut surface);
+                let clips = bar.layers[0].draw(&cfg, WIDTH as i32, HEIGHT as i32, &surface, shift, false, None);
+                let after = pixels(&mut surface);
+                let mut complete = ImageSurface::create(Format::ARgb32, HEIGHT as i32, WIDTH as i32).unwrap();
+                bar.layers[0].draw(&cfg, WIDTH as i32, HEIGHT as i32, &complete, shift, true, None);
+                assert!(after == pixels(&mut complete), "partial redraw differs from a complete one ({})", orientation);
+                // only the clips are copied to the display, whatever changed has to be in one
+                let changed = (0..after.len()).filter(|i| before[*i] != after[*i]).map(|i| ((i % stride) / 4, i / stride));
+                let mut changed_count = 0;
+                for (px, py) in changed {
+                    changed_count += 1;
+                    assert!(
+                        clips.iter().any(|clip| {
+                            (clip.x1() as usize..clip.x2() as usize).contains(&px) && (clip.y1() as usize..clip.y2() as usize).contains(&py)
+                        }),
+                        "pixel {}, {} changed outside of the clips ({})", px, py, orientation
+                    );
+                }
+                assert!(changed_count > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn column_span_covers_whole_pixels_and_a_margin() {
+        assert_eq!(column_span(100, 10.5, 20.25), (9.0, 22.0));
+        assert_eq!(column_span(100, 10.0, 20.0), (9.0, 21.0));
+        // but not beyond the bar
+        assert_eq!(column_span(100, 0.5, 99.5), (0.0, 100.0));
+    }
+
+    #[test]
+    fn column_clip_rounds_outwards_and_follows_the_orientation() {
+        let clip = column_clip(PanelOrientation::RightSideUp, 2008, 60, 9.5, 21.25);
+        assert_eq!((clip.x1(), clip.y1(), clip.x2(), clip.y2()), (0, 9, 60, 22));
+        let clip = column_clip(PanelOrientation::LeftSideUp, 2008, 60, 9.5, 21.25);
+        assert_eq!((clip.x1(), clip.y1(), clip.x2(), clip.y2()), (0, 2008 - 22, 60, 2008 - 9));
+        let clip = column_clip(PanelOrientation::RightSideUp, 2008, 60, -1.0, 2010.0);
+        assert_eq!((clip.x1(), clip.y1(), clip.x2(), clip.y2()), (0, 0, 60, 2008));
+    }
+
+    #[test]
+    fn taps_go_to_the_button_drawn_under_them() {
+        let (mut cfg, _) = test_bar("");
+        let mut rng = StdRng::seed_from_u64(402);
+        let y = HEIGHT as f64 / 2.0;
+        for _ in 0..2000 {
+            let count = rng.gen_range(1..=24);
+            let width = rng.gen_range(400..=2600);
+            cfg.enable_pixel_shift = rng.gen_bool(0.5);
+            for inset in [&mut cfg.left_inset, &mut cfg.right_inset] {
+                *inset = if rng.gen_bool(0.5) { rng.gen_range(0.0..0.1) } else { rng.gen_range(0..100) as f64 };
+            }
+            let (span, origin) = layout_span(&cfg, width);
+            let pixel_shift_width = if cfg.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX as i32 } else { 0 };
+            assert_eq!(origin as i32 + span + inset_px(cfg.right_inset, width) + pixel_shift_width / 2, width);
+            let spacing = if rng.gen_bool(0.5) { BUTTON_SPACING_PX } else { COMPACT_BUTTON_SPACING_PX };
+            let mut geometry = LayerGeometry::new(count, span, origin, spacing);
+            if geometry.button_width < 1.0 {
+                // more buttons than fit
+                continue;
+            }
+            if rng.gen_bool(0.3) {
+                let button_width = rng.gen_range(1.0..=geometry.button_width);
+                geometry = geometry.shrink_to(button_width, span);
+            }
+            let drawn_at = |x: f64| (0..count).find(|i| x >= geometry.left_edge(*i) && x <= geometry.left_edge(*i) + geometry.button_width);
+            let edges = (0..count).flat_map(|i| {
+                let left = geometry.left_edge(i);
+                [left - 0.5, left, left + 0.5, left + geometry.button_width, left + geometry.button_width + 0.5]
+            });
+            let random = (0..50).map(|_| rng.gen_range(-20.0..width as f64 + 20.0)).collect::<Vec<_>>();
+            for x in edges.chain(random) {
+                let hit = geometry.button_at(x).filter(|btn| geometry.hit(*btn, HEIGHT, x, y));
+                assert_eq!(hit, drawn_at(x), "{} buttons {} wide from {}, tap at {}", count, geometry.button_width, origin, x);
+            }
+            for i in 1..count {
+                assert!(geometry.left_edge(i - 1) + geometry.button_width < geometry.left_edge(i), "buttons {} and {} overlap", i - 1, i);
+            }
+        }
+    }
+
+    #[test]
+    fn insets_are_fractions_or_pixels() {
+        let mut rng = StdRng::seed_from_u64(402);
+        for _ in 0..1000 {
+            let width = rng.gen_range(400..=2600);
+            let fraction = rng.gen_range(0.0..1.0);
+            let px = inset_px(fraction, width);
+            assert!((px as f64 - fraction * width as f64).abs() <= 0.5);
+            let pixels = rng.gen_range(1.0..200.0);
+            assert_eq!(inset_px(pixels, width), pixels.round() as i32);
+        }
+        assert_eq!(inset_px(-0.5, 2008), 0);
+    }
+
+    // Differences up to this much per channel are antialiasing, which may change
+    // between cairo versions
+    const SNAPSHOT_TOLERANCE: u8 = 24;
+
+    // Compares surface with src/snapshots/name.png, run the tests with
+    // TINY_DFR_BLESS_SNAPSHOTS=1 to write the references again after a deliberate change
+    fn assert_snapshot(name: &str, surface: &mut ImageSurface) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/snapshots").join(format!("{}.png", name));
+        if std::env::var_os("TINY_DFR_BLESS_SNAPSHOTS").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            surface.write_to_png(&mut File::create(&path).unwrap()).unwrap();
+            return;
+        }
+        let mut reference = ImageSurface::create_from_png(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!((reference.width(), reference.height()), (surface.width(), surface.height()), "{} changed size", name);
+        let stride = surface.stride() as usize;
+        let expected = pixels(&mut reference);
+        let actual = pixels(surface);
+        if let Some(i) = (0..actual.len()).find(|i| actual[*i].abs_diff(expected[*i]) > SNAPSHOT_TOLERANCE) {
+            panic!("{} differs from its snapshot at pixel {}, {}", name, (i % stride) / 4, i / stride);
+        }
+    }
+
+    fn render(bar: &mut State, cfg: &Config, hints: Option<&[String]>) -> ImageSurface {
+        let surface = ImageSurface::create(Format::ARgb32, HEIGHT as i32, WIDTH as i32).unwrap();
+        bar.layers[bar.active_layer].draw(cfg, WIDTH as i32, HEIGHT as i32, &surface, (0.0, 0.0), true, hints);
+        surface
+    }
+
+    #[test]
+    fn layers_render_like_their_snapshots() {
+        let (cfg, mut bar) = test_bar("");
+        assert_snapshot("primary", &mut render(&mut bar, &cfg, None));
+        bar.active_layer = 1;
+        assert_snapshot("media", &mut render(&mut bar, &cfg, None));
+        let hints = ["F5", "F6", "F7", "F8"].map(String::from);
+        assert_snapshot("media_hints", &mut render(&mut bar, &cfg, Some(&hints)));
+    }
+
+    #[test]
+    fn pressed_buttons_render_like_their_snapshots() {
+        let (cfg, mut bar) = test_bar("PressFlashMs = 0");
+        let mut uinput = FakeUinput::new();
+        let (x, y) = on_button(&bar, &cfg, 1, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_snapshot("primary_pressed", &mut render(&mut bar, &cfg, None));
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        bar.active_layer = 1;
+        let (x, y) = on_button(&bar, &cfg, 1, true);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_snapshot("media_secondary_pressed", &mut render(&mut bar, &cfg, None));
+    }
+
+    #[test]
+    fn themes_render_like_their_snapshots() {
+        let (cfg, mut bar) = test_bar(r##"
+            Layout = "Compact"
+            ShowButtonOutlines = false
+            LeftInset = 40
+            PrimaryLayerKeys = [
+                { Text = "F1", Action = "F1", Color = "#b00020", TextColor = "#000000" },
+                { Text = "F2", Action = "F2", Color = "#ffffff40" },
+                { Text = "Mute", Action = "Mute" },
+                { Text = "Shift", Action = "LeftShift", Latch = true, LatchedColor = "#1565c0" }
+            ]
+        "##);
+        assert_snapshot("compact_colors", &mut render(&mut bar, &cfg, None));
+    }
+
+    #[test]
+    fn touch_waking_the_clock_is_swallowed() {
+        let (cfg, mut bar) = test_bar("IdleMode = \"Clock\"\nBackgroundAction = \"NextLayer\"");
+        let mut uinput = FakeUinput::new();
+        bar.clock = Some("12:00".to_string());
+        let (x, y) = on_button(&bar, &cfg, 1, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        // the main loop takes the clock down once the touch woke the bar
+        bar.clock = None;
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 0, x: x + 1.0, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), []);
+        assert!(bar.touches.is_empty());
+        bar.clock = Some("12:00".to_string());
+        let (action, _) = touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 1, x: 100.0, y: 2.0 });
+        assert_eq!(action, None);
+        // only the first touch
+        bar.clock = None;
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        assert_eq!(uinput.keys(), [(Key::F2 as u16, 1)]);
+    }
+
+    const GLYPH_SET_CFG: &str = r#"
+        MediaLayerKeys = [{ Text = "Up", Action = "VolumeUp" }]
+        MediaLayerGlyphs = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"]
+        GlyphsPerPage = 4
+    "#;
+
+    fn shown_glyphs(bar: &State) -> Vec<String> {
+        bar.layers[bar.active_layer].buttons.iter().filter_map(|button| match &button.action {
+            ButtonAction::Glyph(glyph) => Some(glyph.clone()),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn glyph_sets_are_paged() {
+        let (cfg, mut bar) = test_bar(GLYPH_SET_CFG);
+        let mut uinput = FakeUinput::new();
+        bar.active_layer = 1;
+        // the key, the page button and the first page
+        assert_eq!(bar.layers[1].buttons.len(), 6);
+        assert_eq!(shown_glyphs(&bar), ["a", "b", "c", "d"]);
+        for (right_half, glyphs) in [(true, vec!["e", "f", "g", "h"]), (true, vec!["i", "j"]), (true, vec!["a", "b", "c", "d"]), (false, vec!["i", "j"])] {
+            let (x, y) = on_button(&bar, &cfg, 1, right_half);
+            let (action, _) = touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+            let action = action.unwrap();
+            assert!(!bar.apply_layer_action(action, &mut uinput.handle));
+            touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+            assert_eq!(shown_glyphs(&bar), glyphs);
+            assert_eq!(bar.active_layer, 1);
+        }
+        assert_eq!(uinput.keys(), []);
+        let (pressed, _) = press_and_lift(&mut bar, &cfg, &mut uinput, 3);
+        assert!(!pressed.is_empty());
+    }
+
+    #[test]
+    fn turning_the_page_ends_touches_on_glyphs() {
+        let (cfg, mut bar) = test_bar(GLYPH_SET_CFG);
+        let mut uinput = FakeUinput::new();
+        bar.active_layer = 1;
+        let (x, y) = on_button(&bar, &cfg, 2, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Down { slot: 0, x, y });
+        bar.apply_layer_action(LayerAction::NextPage, &mut uinput.handle);
+        assert!(bar.touches.is_empty());
+        uinput.keys();
+        // sliding over the new page does not type anything
+        let (x, y) = on_button(&bar, &cfg, 3, false);
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Motion { slot: 0, x, y });
+        touch(&mut bar, &cfg, &mut uinput, TouchInput::Up { slot: 0 });
+        assert_eq!(uinput.keys(), []);
+        assert!(bar.layers[1].buttons.iter().all(|button| !button.active));
+        // and the paged out glyphs are not left pressed
+        bar.apply_layer_action(LayerAction::PreviousPage, &mut uinput.handle);
+        assert!(bar.layers[1].buttons.iter().all(|button| !button.active));
     }
 }
@@ -0,0 +1,82 @@
+use std::{
+    cmp::Reverse,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OverlayPriority {
+    Low,
+    Normal,
+    High
+}
+
+struct Overlay {
+    // overlays pushed with the same id replace each other
+    id: &'static str,
+    text: String,
+    priority: OverlayPriority,
+    duration: Duration,
+    // the duration only counts down while the overlay is visible
+    shown_since: Option<Instant>
+}
+
+// Transient full-bar messages shown on top of the active layer.
+// Only the overlay with the highest priority is visible, the others wait
+// in the queue, and the active layer is shown again once all of them expire.
+pub struct OverlayManager {
+    overlays: Vec<Overlay>,
+    changed: bool
+}
+
+impl OverlayManager {
+    pub fn new() -> OverlayManager {
+        OverlayManager {
+            overlays: Vec::new(),
+            changed: false
+        }
+    }
+
+    pub fn push(&mut self, id: &'static str, text: String, priority: OverlayPriority, duration: Duration) {
+        let overlay = Overlay { id, text, priority, duration, shown_since: None };
+        match self.overlays.iter_mut().find(|o| o.id == id) {
+            Some(existing) => *existing = overlay,
+            None => self.overlays.push(overlay)
+        }
+        self.changed = true;
+    }
+
+    fn visible(&self) -> Option<usize> {
+        self.overlays.iter()
+            .enumerate()
+            .max_by_key(|(i, o)| (o.priority, Reverse(*i)))
+            .map(|(i, _)| i)
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.visible().map(|i| self.overlays[i].text.as_str())
+    }
+
+    // Returns whether the bar needs to be redrawn and the time until the next update is needed
+    pub fn update(&mut self) -> (bool, i32) {
+        let now = Instant::now();
+        if let Some(i) = self.visible() {
+            let overlay = &self.overlays[i];
+            if overlay.shown_since.map_or(false, |since| now - since >= overlay.duration) {
+                self.overlays.remove(i);
+                self.changed = true;
+            }
+        }
+        let visible = self.visible();
+        let mut next_timeout_ms = i32::MAX;
+        for (i, overlay) in self.overlays.iter_mut().enumerate() {
+            if Some(i) != visible {
+                overlay.shown_since = None;
+                continue;
+            }
+            let since = *overlay.shown_since.get_or_insert(now);
+            let remaining = overlay.duration.saturating_sub(now - since);
+            next_timeout_ms = remaining.as_millis().min(i32::MAX as u128) as i32;
+        }
+        (std::mem::take(&mut self.changed), next_timeout_ms)
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    fs,
+    time::Instant,
+};
+
+const POWER_SUPPLY_DIR: &'static str = "/sys/class/power_supply";
+// sysfs attributes can not be watched, so the power state is polled
+const POLL_INTERVAL_MS: i32 = 5 * 1000;
+
+// Adapters are found by type rather than by name, as their names differ
+// between machines, e.g. ADP1 on Intel Macs and macsmc-ac on Apple Silicon.
+// Returns None when there is no adapter at all
+fn read_on_ac() -> Option<bool> {
+    let mut found = false;
+    for entry in fs::read_dir(POWER_SUPPLY_DIR).ok()?.flatten() {
+        let path = entry.path();
+        let is_mains = fs::read_to_string(path.join("type")).map_or(false, |t| t.trim() == "Mains");
+        if !is_mains {
+            continue;
+        }
+        found = true;
+        if fs::read_to_string(path.join("online")).map_or(false, |o| o.trim() == "1") {
+            return Some(true);
+        }
+    }
+    found.then_some(false)
+}
+
+pub struct PowerManager {
+    on_ac: bool,
+    checked_at: Instant
+}
+
+impl PowerManager {
+    pub fn new() -> PowerManager {
+        PowerManager {
+            // machines without an adapter are treated as plugged in
+            on_ac: read_on_ac().unwrap_or(true),
+            checked_at: Instant::now()
+        }
+    }
+
+    pub fn on_ac(&self) -> bool {
+        self.on_ac
+    }
+
+    // Returns whether the power state changed and the time until it should be checked again
+    pub fn update(&mut self) -> (bool, i32) {
+        let elapsed = self.checked_at.elapsed().as_millis().min(i32::MAX as u128) as i32;
+        if elapsed < POLL_INTERVAL_MS {
+            return (false, POLL_INTERVAL_MS - elapsed);
+        }
+        self.checked_at = Instant::now();
+        let on_ac = read_on_ac().unwrap_or(true);
+        let changed = on_ac != self.on_ac;
+        self.on_ac = on_ac;
+        (changed, POLL_INTERVAL_MS)
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
+use anyhow::Result;
+
+// Lives in /run so that it survives daemon restarts, but not reboots
+const STATE_DIR: &'static str = "/run/tiny-dfr";
+const STATE_PATH: &'static str = "/run/tiny-dfr/state";
+
+// Identifies a button as its layer, its index in the layer and whether it is the secondary half
+pub type ButtonId = (usize, usize, bool);
+
+#[derive(Clone, PartialEq, Default)]
+pub struct PersistentState {
+    pub active_layer: usize,
+    pub fn_latched: bool,
+    // latching buttons that are latched, only those with PersistLatch
+    pub latched: Vec<ButtonId>
+}
+
+fn parse_button_id(id: &str) -> Option<ButtonId> {
+    let mut parts = id.split(':');
+    let layer = parts.next()?.parse().ok()?;
+    let button = parts.next()?.parse().ok()?;
+    let secondary = parts.next()? == "1";
+    Some((layer, button, secondary))
+}
+
+impl PersistentState {
+    fn parse(contents: &str) -> PersistentState {
+        let mut state = PersistentState::default();
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("ActiveLayer", value)) => state.active_layer = value.parse().unwrap_or(0),
+                Some(("FnLatched", value)) => state.fn_latched = value == "true",
+                Some(("Latched", value)) => state.latched = value.split(',').filter_map(parse_button_id).collect(),
+                _ => {}
+            }
+        }
+        state
+    }
+}
+
+pub struct StateManager {
+    // opened before dropping privileges, as nobody can not create files in /run
+    file: Option<File>,
+    saved: PersistentState
+}
+
+fn open_state_file() -> Result<File> {
+    fs::create_dir_all(STATE_DIR)?;
+    Ok(OpenOptions::new().read(true).write(true).create(true).open(STATE_PATH)?)
+}
+
+impl StateManager {
+    pub fn new() -> StateManager {
+        let mut file = open_state_file()
+            .map_err(|e| eprintln!("Failed to open {}, state will not be persisted: {}", STATE_PATH, e))
+            .ok();
+        let mut contents = String::new();
+        if let Some(file) = &mut file {
+            let _ = file.read_to_string(&mut contents);
+        }
+        StateManager {
+            file,
+            saved: PersistentState::parse(&contents)
+        }
+    }
+
+    pub fn get(&self) -> &PersistentState {
+        &self.saved
+    }
+
+    pub fn save_layer(&mut self, active_layer: usize, fn_latched: bool) {
+        if (active_layer, fn_latched) == (self.saved.active_layer, self.saved.fn_latched) {
+            return;
+        }
+        self.saved.active_layer = active_layer;
+        self.saved.fn_latched = fn_latched;
+        self.write();
+    }
+
+    pub fn save_latched(&mut self, latched: Vec<ButtonId>) {
+        if latched == self.saved.latched {
+            return;
+        }
+        self.saved.latched = latched;
+        self.write();
+    }
+
+    fn write(&mut self) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let state = &self.saved;
+        let latched: Vec<String> = state.latched.iter()
+            .map(|(layer, button, secondary)| format!("{}:{}:{}", layer, button, *secondary as u8))
+            .collect();
+        let contents = format!(
+            "ActiveLayer={}\nFnLatched={}\nLatched={}\n",
+            state.active_layer, state.fn_latched, latched.join(",")
+        );
+        let res = file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(contents.as_bytes()));
+        if let Err(e) = res {
+            eprintln!("Failed to write {}: {}", STATE_PATH, e);
+        }
+    }
+}